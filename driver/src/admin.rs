@@ -0,0 +1,418 @@
+use crate::args::{Args, Command, MetadataCommand, OutputFormat};
+use crate::config::{Configuration, GcAction};
+use crate::control::{ControlModule, TrustedHostKey};
+use crate::error::{AppError, Result};
+use crate::metadata::{Metadata, Storeable};
+use crate::storage::{
+    GarbageCollector, OrphanRecord, Publication, Staging, StorageInfo, VolumeState,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// Records of every kind, keyed by kind and then by record key
+type Export = BTreeMap<String, BTreeMap<String, Value>>;
+
+/// Wraps an exported record that could not be decoded, import leaves it out
+const UNDECODABLE: &str = "undecodable";
+
+/// The kinds of record kept in the metadata database
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    StorageInfo,
+    Publication,
+    TrustedHostKey,
+    Orphan,
+    Staging,
+    VolumeState,
+}
+
+impl Kind {
+    const ALL: &'static [Kind] = &[
+        Kind::StorageInfo,
+        Kind::Publication,
+        Kind::TrustedHostKey,
+        Kind::Orphan,
+        Kind::Staging,
+        Kind::VolumeState,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::StorageInfo => StorageInfo::KEY,
+            Kind::Publication => Publication::KEY,
+            Kind::TrustedHostKey => TrustedHostKey::KEY,
+            Kind::Orphan => OrphanRecord::KEY,
+            Kind::Staging => Staging::KEY,
+            Kind::VolumeState => VolumeState::KEY,
+        }
+    }
+
+    fn parse(name: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .find(|k| k.name().eq_ignore_ascii_case(name))
+            .copied()
+            .ok_or_else(|| {
+                AppError::InvalidArgument(format!(
+                    "'{}' is not a record kind, use one of {:?}",
+                    name,
+                    Self::ALL.iter().map(|k| k.name()).collect::<Vec<_>>()
+                ))
+            })
+    }
+
+    async fn keys(&self, metadata: &Metadata) -> Result<Vec<String>> {
+        match self {
+            Kind::StorageInfo => metadata.keys::<StorageInfo>().await,
+            Kind::Publication => metadata.keys::<Publication>().await,
+            Kind::TrustedHostKey => metadata.keys::<TrustedHostKey>().await,
+            Kind::Orphan => metadata.keys::<OrphanRecord>().await,
+            Kind::Staging => metadata.keys::<Staging>().await,
+            Kind::VolumeState => metadata.keys::<VolumeState>().await,
+        }
+    }
+
+    async fn list(&self, metadata: &Metadata) -> Result<BTreeMap<String, Value>> {
+        match self {
+            Kind::StorageInfo => list::<StorageInfo>(metadata).await,
+            Kind::Publication => list::<Publication>(metadata).await,
+            Kind::TrustedHostKey => list::<TrustedHostKey>(metadata).await,
+            Kind::Orphan => list::<OrphanRecord>(metadata).await,
+            Kind::Staging => list::<Staging>(metadata).await,
+            Kind::VolumeState => list::<VolumeState>(metadata).await,
+        }
+    }
+
+    async fn scan_raw(&self, metadata: &Metadata) -> Result<Vec<(String, Vec<u8>)>> {
+        match self {
+            Kind::StorageInfo => metadata.scan_raw::<StorageInfo>().await,
+            Kind::Publication => metadata.scan_raw::<Publication>().await,
+            Kind::TrustedHostKey => metadata.scan_raw::<TrustedHostKey>().await,
+            Kind::Orphan => metadata.scan_raw::<OrphanRecord>().await,
+            Kind::Staging => metadata.scan_raw::<Staging>().await,
+            Kind::VolumeState => metadata.scan_raw::<VolumeState>().await,
+        }
+    }
+
+    async fn set_raw(&self, metadata: &Metadata, key: &str, val: Vec<u8>) -> Result<()> {
+        match self {
+            Kind::StorageInfo => metadata.set_raw::<StorageInfo>(key, val).await,
+            Kind::Publication => metadata.set_raw::<Publication>(key, val).await,
+            Kind::TrustedHostKey => metadata.set_raw::<TrustedHostKey>(key, val).await,
+            Kind::Orphan => metadata.set_raw::<OrphanRecord>(key, val).await,
+            Kind::Staging => metadata.set_raw::<Staging>(key, val).await,
+            Kind::VolumeState => metadata.set_raw::<VolumeState>(key, val).await,
+        }
+    }
+
+    async fn get(&self, metadata: &Metadata, key: &str) -> Result<Option<Value>> {
+        match self {
+            Kind::StorageInfo => get::<StorageInfo>(metadata, key).await,
+            Kind::Publication => get::<Publication>(metadata, key).await,
+            Kind::TrustedHostKey => get::<TrustedHostKey>(metadata, key).await,
+            Kind::Orphan => get::<OrphanRecord>(metadata, key).await,
+            Kind::Staging => get::<Staging>(metadata, key).await,
+            Kind::VolumeState => get::<VolumeState>(metadata, key).await,
+        }
+    }
+
+    async fn delete(&self, metadata: &Metadata, key: &str) -> Result<()> {
+        match self {
+            Kind::StorageInfo => metadata.delete::<StorageInfo>(key).await,
+            Kind::Publication => metadata.delete::<Publication>(key).await,
+            Kind::TrustedHostKey => metadata.delete::<TrustedHostKey>(key).await,
+            Kind::Orphan => metadata.delete::<OrphanRecord>(key).await,
+            Kind::Staging => metadata.delete::<Staging>(key).await,
+            Kind::VolumeState => metadata.delete::<VolumeState>(key).await,
+        }
+    }
+
+    fn check(&self, key: &str, val: &Value) -> Result<()> {
+        match self {
+            Kind::StorageInfo => decode::<StorageInfo>(key, val).map(|_| ()),
+            Kind::Publication => decode::<Publication>(key, val).map(|_| ()),
+            Kind::TrustedHostKey => decode::<TrustedHostKey>(key, val).map(|_| ()),
+            Kind::Orphan => decode::<OrphanRecord>(key, val).map(|_| ()),
+            Kind::Staging => decode::<Staging>(key, val).map(|_| ()),
+            Kind::VolumeState => decode::<VolumeState>(key, val).map(|_| ()),
+        }
+    }
+
+    async fn set(&self, metadata: &Metadata, key: &str, val: &Value) -> Result<()> {
+        match self {
+            Kind::StorageInfo => metadata.set(key, decode::<StorageInfo>(key, val)?).await,
+            Kind::Publication => metadata.set(key, decode::<Publication>(key, val)?).await,
+            Kind::TrustedHostKey => metadata.set(key, decode::<TrustedHostKey>(key, val)?).await,
+            Kind::Orphan => metadata.set(key, decode::<OrphanRecord>(key, val)?).await,
+            Kind::Staging => metadata.set(key, decode::<Staging>(key, val)?).await,
+            Kind::VolumeState => metadata.set(key, decode::<VolumeState>(key, val)?).await,
+        }
+    }
+}
+
+/// Every record of the type, those that can't be decoded are kept as stored
+async fn list<T: Storeable>(metadata: &Metadata) -> Result<BTreeMap<String, Value>> {
+    let mut result = BTreeMap::new();
+    for (key, bytes) in metadata.scan_raw::<T>().await? {
+        let val = match Metadata::decode_raw::<T>(&key, &bytes) {
+            Ok(val) => serde_json::to_value(val)?,
+            Err(e) => undecodable(&bytes, e),
+        };
+        result.insert(key, val);
+    }
+    Ok(result)
+}
+
+/// The marker written in place of a record that can't be decoded, the stored value is kept as
+/// JSON when it is JSON and as hex otherwise
+fn undecodable(bytes: &[u8], error: AppError) -> Value {
+    let raw = serde_json::from_slice(bytes).unwrap_or_else(|_| {
+        Value::from(
+            bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+        )
+    });
+    serde_json::json!({ UNDECODABLE: { "error": error.to_string(), "raw": raw } })
+}
+
+async fn get<T: Storeable>(metadata: &Metadata, key: &str) -> Result<Option<Value>> {
+    match metadata.get::<T>(key).await? {
+        Some(val) => Ok(Some(serde_json::to_value(val)?)),
+        None => Ok(None),
+    }
+}
+
+fn decode<T: Storeable>(key: &str, val: &Value) -> Result<T> {
+    serde_json::from_value(val.clone()).map_err(|e| {
+        AppError::InvalidArgument(format!("Invalid {} record '{}': {}", T::KEY, key, e))
+    })
+}
+
+fn format<T: Serialize>(val: &T, output: OutputFormat) -> Result<String> {
+    Ok(match output {
+        OutputFormat::Json => serde_json::to_string_pretty(val)?,
+        OutputFormat::Yaml => serde_yaml::to_string(val)?,
+    })
+}
+
+/// The `metadata` subcommands, they work on the database directly so the driver must be stopped
+pub async fn metadata(args: &Args, command: &MetadataCommand) -> Result<()> {
+    let config = Configuration::new(args)?;
+    let backend = match command {
+        MetadataCommand::Migrate {
+            from: Some(from), ..
+        } => config.resolve_metadata_backend(from),
+        _ => config.metadata_backend(),
+    };
+    let metadata = Metadata::new(&backend, &args.metadata_db, false)
+        .await
+        .map_err(|e| {
+            AppError::Generic(format!(
+                "Could not open metadata {:?}, is the driver still running? {}",
+                backend, e
+            ))
+        })?;
+    match command {
+        MetadataCommand::List { kind, output } => {
+            let kinds = match kind {
+                Some(k) => vec![Kind::parse(k)?],
+                None => Kind::ALL.to_vec(),
+            };
+            let mut result = BTreeMap::new();
+            for kind in kinds {
+                // Volumes are listed with their storage info, other kinds by key
+                let val = match kind {
+                    Kind::StorageInfo => serde_json::to_value(kind.list(&metadata).await?)?,
+                    _ => serde_json::to_value(kind.keys(&metadata).await?)?,
+                };
+                result.insert(kind.name(), val);
+            }
+            println!("{}", format(&result, *output)?);
+        }
+        MetadataCommand::Get { kind, key, output } => {
+            let kind = Kind::parse(kind)?;
+            let val = kind
+                .get(&metadata, key)
+                .await?
+                .ok_or_else(|| AppError::Generic(format!("No {} record '{}'", kind.name(), key)))?;
+            println!("{}", format(&val, *output)?);
+        }
+        MetadataCommand::Export { output, file } => {
+            let mut export = Export::new();
+            for kind in Kind::ALL {
+                export.insert(kind.name().to_string(), kind.list(&metadata).await?);
+            }
+            let text = format(&export, *output)?;
+            match file {
+                Some(path) => {
+                    std::fs::write(path, text)?;
+                    let count: usize = export.values().map(|r| r.len()).sum();
+                    info!("Exported {} records to {}", count, path.display());
+                }
+                None => println!("{}", text),
+            }
+        }
+        MetadataCommand::Import { file } => {
+            let mut text = String::new();
+            if file.as_os_str() == "-" {
+                std::io::stdin().read_to_string(&mut text)?;
+            } else {
+                text = std::fs::read_to_string(file)?;
+            }
+            // YAML is a superset of JSON, either format of export is accepted
+            let import: Export = serde_yaml::from_str(&text).map_err(|e| {
+                AppError::InvalidArgument(format!("Invalid export {}: {}", file.display(), e))
+            })?;
+            let mut records = vec![];
+            for (name, entries) in import.iter() {
+                let kind = Kind::parse(name)?;
+                for (key, val) in entries.iter() {
+                    if val.get(UNDECODABLE).is_some() {
+                        warn!("Skipping undecodable {} record '{}'", kind.name(), key);
+                        continue;
+                    }
+                    kind.check(key, val)?;
+                    records.push((kind, key, val));
+                }
+            }
+            for (kind, key, val) in records.iter() {
+                kind.set(&metadata, key, val).await?;
+            }
+            info!("Imported {} records from {}", records.len(), file.display());
+        }
+        MetadataCommand::Migrate { to, .. } => {
+            let target = config.resolve_metadata_backend(to);
+            if target == backend {
+                return Err(AppError::InvalidArgument(
+                    "The source and target backends are the same".into(),
+                ));
+            }
+            let target = Metadata::new(&target, &args.metadata_db, false).await?;
+            let mut count = 0;
+            for kind in Kind::ALL {
+                // Copied as stored, so records keep their schema version and unreadable
+                // ones are not lost. Legacy bincode records are wrapped in an envelope.
+                for (key, val) in kind.scan_raw(&metadata).await? {
+                    kind.set_raw(&target, &key, val).await.map_err(|e| {
+                        AppError::Generic(format!(
+                            "Could not copy {} record '{}': {}",
+                            kind.name(),
+                            key,
+                            e
+                        ))
+                    })?;
+                    count += 1;
+                }
+            }
+            info!("Copied {} records to {:?}", count, to);
+        }
+        MetadataCommand::Delete { kind, key } => {
+            let kind = Kind::parse(kind)?;
+            // Records are not decoded so that unreadable ones can be removed
+            if !kind.keys(&metadata).await?.contains(key) {
+                return Err(AppError::Generic(format!(
+                    "No {} record '{}'",
+                    kind.name(),
+                    key
+                )));
+            }
+            kind.delete(&metadata, key).await?;
+            info!("Deleted {} record '{}'", kind.name(), key);
+        }
+    }
+    Ok(())
+}
+
+/// The `gc` subcommand, a dry run unless `--delete` or `--archive` is given
+pub async fn gc(args: &Args) -> Result<()> {
+    let (delete, archive, grace_period, parent_dataset, profile, output) = match &args.command {
+        Some(Command::Gc {
+            delete,
+            archive,
+            grace_period,
+            parent_dataset,
+            profile,
+            output,
+        }) => (
+            delete,
+            archive,
+            grace_period,
+            parent_dataset,
+            profile,
+            output,
+        ),
+        _ => return Ok(()),
+    };
+    let config = Configuration::new(args)?;
+    let backend = config.metadata_backend();
+    let metadata = Metadata::new(&backend, &args.metadata_db, false)
+        .await
+        .map_err(|e| {
+            AppError::Generic(format!(
+                "Could not open metadata {:?}, is the driver still running? {}",
+                backend, e
+            ))
+        })?;
+    let control_mode = match profile {
+        Some(name) => config.control_profiles.get(name).ok_or_else(|| {
+            AppError::InvalidArgument(format!("No control profile named '{}'", name))
+        })?,
+        None => config.controller.control_mode.as_ref().ok_or_else(|| {
+            AppError::InvalidArgument(
+                "controller.control_mode is not configured, pass --profile".into(),
+            )
+        })?,
+    };
+    let control = ControlModule::new(control_mode, &metadata)?;
+    control.connect().await?;
+
+    let action = if *delete {
+        GcAction::Delete
+    } else if *archive {
+        GcAction::Archive
+    } else {
+        GcAction::Report
+    };
+    let grace_period = grace_period.unwrap_or(config.gc.grace_period);
+    let gc = GarbageCollector::new(
+        control,
+        metadata,
+        &config,
+        action,
+        grace_period,
+        parent_dataset,
+    )
+    .await?;
+    let orphans = gc.run().await?;
+    println!("{}", format(&orphans, *output)?);
+    ControlModule::shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lists_undecodable_records_as_stored() {
+        let metadata = Metadata::temporary().unwrap();
+        metadata
+            .set_raw::<Staging>("json", br#"{"version":1,"data":{"unknown":true}}"#.to_vec())
+            .await
+            .unwrap();
+        metadata
+            .set_raw::<Staging>("binary", vec![0x01, 0xff])
+            .await
+            .unwrap();
+
+        let records = Kind::Staging.list(&metadata).await.unwrap();
+        let json = &records["json"][UNDECODABLE];
+        assert_eq!(json["raw"]["data"]["unknown"], Value::Bool(true));
+        assert!(json["error"].as_str().unwrap().contains("json"));
+        assert_eq!(records["binary"][UNDECODABLE]["raw"], "01ff");
+    }
+}
@@ -1,10 +1,19 @@
+use crate::check;
 use crate::config::Configuration;
-use crate::control::ControlModule;
-use crate::error::Result;
+use crate::control::{self, ControlModule, TrustedHostKey};
+use crate::error::{AppError, Result};
+use crate::storage::{
+    GarbageCollector, OrphanRecord, Publication, Staging, Storage, StorageInfo, VolumeLocks,
+    VolumeState,
+};
 use crate::{args::Args, metadata::Metadata};
 use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::{signal, sync::watch, time};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{sync::watch, time};
+
+const STARTUP_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(30);
 
 #[derive(Debug, Deref, DerefMut, Clone)]
 pub struct App(Arc<InnerApp>);
@@ -12,52 +21,254 @@ pub struct App(Arc<InnerApp>);
 #[derive(Debug)]
 pub struct InnerApp {
     pub node_id: String,
-    pub config: Configuration,
+    /// Replaced on SIGHUP, requests take a snapshot with `App::config`
+    config: RwLock<Configuration>,
+    pub config_path: PathBuf,
     pub csi_path: PathBuf,
     pub csi_name: String,
     pub shutdown_tx: watch::Sender<bool>,
     pub shutdown_rx: watch::Receiver<bool>,
     pub metadata: Metadata,
+    /// Set once the startup checks pass, reported by the Probe call
+    pub ready: AtomicBool,
+    pub volume_locks: VolumeLocks,
 }
 
 impl App {
-    pub fn new(args: Args) -> Result<Self> {
+    pub async fn new(args: Args) -> Result<Self> {
         let (shutdown_tx, shutdown_rx) = watch::channel(false);
-        let node_id = args.node_id.clone();
+        let node_id = args
+            .node_id
+            .clone()
+            .ok_or_else(|| AppError::Generic("A node id is required, set --node-id".into()))?;
         let csi_path = args.csi_path.clone();
+        let config_path = args.config_path.clone();
         let csi_name = args.csi_name.clone();
-        let metadata = match Metadata::new(args.metadata_db.clone()) {
-            Ok(m) => m,
-            Err(e) => {
-                warn!("Failed to load metadata db due to error {}, attempting to reload a new database", e);
-                std::fs::remove_dir_all(&args.metadata_db)?;
-                Metadata::new(args.metadata_db.clone())?
-            }
-        };
-        let config = Configuration::new(args)?;
+        let config = Configuration::new(&args)?;
+        let metadata = Metadata::new(
+            &config.metadata_backend(),
+            &args.metadata_db,
+            args.allow_metadata_reset,
+        )
+        .await?;
+        let csi_name = csi_name
+            .or_else(|| config.driver.name.clone())
+            .ok_or_else(|| {
+                AppError::Generic("A driver name is required, set --csi-name or driver.name".into())
+            })?;
+        control::set_timeouts(config.timeouts.clone());
         Ok(Self(Arc::new(InnerApp {
             node_id,
-            config,
+            config: RwLock::new(config),
+            config_path,
             csi_path,
             csi_name,
             shutdown_tx,
             shutdown_rx,
             metadata,
+            ready: AtomicBool::new(false),
+            volume_locks: VolumeLocks::default(),
         })))
     }
 
-    pub async fn control_node(&self) -> Result<ControlModule> {
-        let cm = ControlModule::new(&self.config.node.control_mode)?;
+    /// The current configuration, it stays unchanged for the holder when it is reloaded
+    pub fn config(&self) -> Configuration {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Re-read the configuration file and replace the current configuration if it is valid.
+    /// Pooled connections of control modes that changed or were removed are dropped.
+    pub fn reload(&self) -> Result<()> {
+        let config = Configuration::load(&self.config_path)?;
+        let report = check::validate(&config);
+        if !report.is_ok() {
+            return Err(AppError::Generic(format!(
+                "Configuration {} is invalid: {}",
+                self.config_path.display(),
+                report.errors.join(", ")
+            )));
+        }
+        if config.driver.name.is_some() && config.driver.name.as_ref() != Some(&self.csi_name) {
+            warn!(
+                "The driver name can't be changed without a restart, it stays '{}'",
+                self.csi_name
+            );
+        }
+
+        let previous = std::mem::replace(&mut *self.config.write().unwrap(), config.clone());
+        if previous.metadata != config.metadata {
+            warn!("The metadata backend can't be changed without a restart, the current one stays in use");
+        }
+        control::set_timeouts(config.timeouts.clone());
+        let current = config.control_modes();
+        for mode in previous.control_modes() {
+            if !current.contains(&mode) {
+                ControlModule::evict(mode);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn control_node(&self, config: &Configuration) -> Result<ControlModule> {
+        let cm = ControlModule::new(&config.node.control_mode, &self.metadata)?;
         cm.connect().await?;
         Ok(cm.into())
     }
 
+    pub async fn control_controller(
+        &self,
+        config: &Configuration,
+    ) -> Result<Option<ControlModule>> {
+        match &config.controller.control_mode {
+            Some(control_mode) => {
+                let cm = ControlModule::new(control_mode, &self.metadata)?;
+                cm.connect().await?;
+                Ok(Some(cm))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Bring every metadata record to its current schema version and record the state of
+    /// volumes created before states were recorded
+    pub async fn migrate_metadata(&self) -> Result<()> {
+        self.metadata.migrate::<StorageInfo>().await?;
+        self.metadata.migrate::<Publication>().await?;
+        self.metadata.migrate::<TrustedHostKey>().await?;
+        self.metadata.migrate::<OrphanRecord>().await?;
+        self.metadata.migrate::<Staging>().await?;
+        self.metadata.migrate::<VolumeState>().await?;
+        VolumeState::backfill(&self.metadata).await?;
+        Ok(())
+    }
+
+    pub async fn reconcile(&self) -> Result<()> {
+        if let Some(control) = self.control_controller(&self.config()).await? {
+            Storage::reconcile(control, &self.metadata, &self.volume_locks).await?;
+        }
+        Ok(())
+    }
+
+    /// Redo the stages and mounts of this node that were lost while the driver was down. The
+    /// volumes are locked before this returns, so their requests wait until they are recovered
+    /// while requests for other volumes are served.
+    pub async fn recover_node(&self) -> Result<()> {
+        let mut volumes = vec![];
+        for staging in Storage::staged_on(&self.metadata, &self.node_id).await? {
+            let lock = self.volume_locks.lock(&staging.volume_id).await;
+            volumes.push((staging, lock));
+        }
+        let zelf = self.clone();
+        tokio::spawn(async move {
+            let config = zelf.config();
+            let result = async {
+                let control = zelf.control_node(&config).await?;
+                Storage::recover_node(control, &zelf.metadata, &zelf.node_id, &config, volumes)
+                    .await
+            };
+            if let Err(e) = result.await {
+                error!("Recovery of staged volumes failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Look for orphans every `gc.interval` while `gc.enabled` is set, the configuration is
+    /// read on every run so a reload can turn collection on or off. Only instances with a
+    /// `controller.control_mode` collect, nodes have no access to the storage server.
+    pub async fn collect_garbage(&self) {
+        loop {
+            let config = self.config();
+            if config.gc.enabled && config.controller.control_mode.is_some() {
+                if let Err(e) = self.collect_garbage_once(&config).await {
+                    error!("Garbage collection failed: {}", e);
+                }
+            }
+            time::sleep(time::Duration::from_secs(config.gc.interval.max(60))).await;
+        }
+    }
+
+    async fn collect_garbage_once(&self, config: &Configuration) -> Result<()> {
+        let control = match self.control_controller(config).await? {
+            Some(control) => control,
+            None => {
+                return Err(AppError::InvalidArgument(
+                    "gc needs controller.control_mode".into(),
+                ))
+            }
+        };
+        let gc = GarbageCollector::new(
+            control,
+            self.metadata.clone(),
+            config,
+            config.gc.action,
+            config.gc.grace_period,
+            &[],
+        )
+        .await?;
+        for orphan in gc.run().await? {
+            match &orphan.outcome {
+                Some(outcome) => info!("Orphaned {} {}: {}", orphan.kind, orphan.name, outcome),
+                None => warn!(
+                    "Orphaned {} {}: {}",
+                    orphan.kind, orphan.name, orphan.reason
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate the configuration and the control targets, retrying until they pass
+    pub async fn startup_checks(&self) {
+        loop {
+            let report = check::check(&self.config(), &self.metadata, false).await;
+            report
+                .warnings
+                .iter()
+                .for_each(|w| warn!("Startup check: {}", w));
+            if report.is_ok() {
+                info!("Startup checks passed, driver is ready");
+                self.ready.store(true, Ordering::SeqCst);
+                return;
+            }
+            report
+                .errors
+                .iter()
+                .for_each(|e| error!("Startup check failed: {}", e));
+            time::sleep(STARTUP_CHECK_INTERVAL).await;
+        }
+    }
+
     pub async fn run(&self) -> Result<()> {
         info!("Init started");
+        self.migrate_metadata().await?;
+
+        let zelf = self.clone();
+        tokio::spawn(async move {
+            info!("Spawning startup check task");
+            zelf.startup_checks().await;
+        });
+
+        let zelf = self.clone();
+        tokio::spawn(async move {
+            info!("Spawning reconciliation task");
+            if let Err(e) = zelf.reconcile().await {
+                error!("Reconciliation of published volumes failed: {}", e);
+            }
+        });
+
+        let zelf = self.clone();
+        tokio::spawn(async move {
+            info!("Spawning garbage collection task");
+            zelf.collect_garbage().await;
+        });
 
         let zelf = self.clone();
         tokio::spawn(async move {
             info!("Spawning CSI task");
+            if let Err(e) = zelf.recover_node().await {
+                error!("Recovery of staged volumes failed: {}", e);
+            }
             match zelf.start_csi_services().await {
                 Ok(_) => {}
                 Err(e) => {
@@ -67,13 +278,29 @@ impl App {
             };
         });
 
-        signal::ctrl_c().await?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut hangup = signal(SignalKind::hangup())?;
+        loop {
+            tokio::select! {
+                _ = interrupt.recv() => break,
+                _ = terminate.recv() => break,
+                _ = hangup.recv() => {
+                    info!("Reloading configuration from {}", self.config_path.display());
+                    match self.reload() {
+                        Ok(_) => info!("Configuration reloaded"),
+                        Err(e) => error!("Configuration reload failed, keeping the current configuration: {}", e),
+                    }
+                }
+            }
+        }
 
         self.shutdown_tx.send(true)?;
         info!("Shutdown signal sent, waiting on services to stop");
         while self.shutdown_tx.receiver_count() > 1 {
             time::sleep(time::Duration::from_millis(100)).await;
         }
+        ControlModule::shutdown().await?;
 
         info!("Shutdown complete");
         Ok(())
@@ -1,4 +1,6 @@
+use crate::config::MetadataBackend;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use structopt::StructOpt;
 
@@ -22,12 +24,138 @@ pub struct Args {
     pub metadata_db: PathBuf,
 
     #[structopt(long)]
-    /// The name of the node this instance is running on
-    pub node_id: String,
+    /// Move an unreadable metadata database aside and start with a new one, records that can
+    /// still be read are copied over
+    pub allow_metadata_reset: bool,
 
     #[structopt(long)]
-    /// The name of the CSI Driver
-    pub csi_name: String,
+    /// The name of the node this instance is running on, required to run the driver
+    pub node_id: Option<String>,
+
+    #[structopt(long)]
+    /// The name of the CSI Driver, defaults to `driver.name` from the configuration
+    pub csi_name: Option<String>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(rename_all = "kebab_case")]
+pub enum Command {
+    /// Validate the configuration and exit, reporting every problem found
+    CheckConfig {
+        #[structopt(long)]
+        /// Also connect to every control target and check that the required tools are installed
+        connect: bool,
+    },
+
+    /// Inspect and edit the metadata database, the driver must not be running
+    Metadata {
+        #[structopt(subcommand)]
+        command: MetadataCommand,
+    },
+
+    /// Find datasets, LIO objects and metadata records that no volume uses. Nothing is removed
+    /// unless --delete or --archive is given, the driver must not be running
+    Gc {
+        #[structopt(long)]
+        /// Destroy orphans that have been seen for longer than the grace period
+        delete: bool,
+        #[structopt(long, conflicts_with = "delete")]
+        /// Move orphaned datasets below `<parent>/metal-csi-archive` instead of destroying them
+        archive: bool,
+        #[structopt(long)]
+        /// Seconds an orphan must have been seen for, `gc.grace_period` of the configuration by default
+        grace_period: Option<u64>,
+        #[structopt(long)]
+        /// Also scan this parent dataset, may be repeated
+        parent_dataset: Vec<String>,
+        #[structopt(long)]
+        /// Scan the server of this control profile instead of the controller's
+        profile: Option<String>,
+        #[structopt(short, long, default_value = "yaml")]
+        /// Output format [json, yaml]
+        output: OutputFormat,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(rename_all = "kebab_case")]
+pub enum MetadataCommand {
+    /// List the storage info of every volume and the keys of the other records, of every kind
+    /// unless one is given
+    List {
+        /// Record kind [StorageInfo, Publication, TrustedHostKey, Orphan, Staging, VolumeState]
+        kind: Option<String>,
+        #[structopt(short, long, default_value = "yaml")]
+        /// Output format [json, yaml]
+        output: OutputFormat,
+    },
+
+    /// Print a single record
+    Get {
+        /// Record kind [StorageInfo, Publication, TrustedHostKey, Orphan, Staging, VolumeState]
+        kind: String,
+        /// Record key, the volume ID for volume records
+        key: String,
+        #[structopt(short, long, default_value = "yaml")]
+        /// Output format [json, yaml]
+        output: OutputFormat,
+    },
+
+    /// Write every record to a file, or to stdout. Records that can't be decoded are written
+    /// as stored under an `undecodable` marker and are skipped by import
+    Export {
+        #[structopt(short, long, default_value = "yaml")]
+        /// Output format [json, yaml]
+        output: OutputFormat,
+        #[structopt(short, long)]
+        /// File to write, stdout if not set
+        file: Option<PathBuf>,
+    },
+
+    /// Add or replace records from an export, every record is checked before any is written
+    Import {
+        /// File to read, JSON or YAML, `-` for stdin
+        file: PathBuf,
+    },
+
+    /// Copy every record to another backend, records already there with the same key are replaced
+    Migrate {
+        #[structopt(long)]
+        /// Backend to read, the configured one if not set [sled[:path], file:path, zfs:dataset]
+        from: Option<MetadataBackend>,
+        #[structopt(long)]
+        /// Backend to write [sled[:path], file:path, zfs:dataset]
+        to: MetadataBackend,
+    },
+
+    /// Remove a single record
+    Delete {
+        /// Record kind [StorageInfo, Publication, TrustedHostKey, Orphan, Staging, VolumeState]
+        kind: String,
+        /// Record key, the volume ID for volume records
+        key: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Json,
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "yaml" | "yml" => Ok(OutputFormat::Yaml),
+            _ => Err(format!("'{}' is not an output format, use json or yaml", s)),
+        }
+    }
 }
 
 impl Args {
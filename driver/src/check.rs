@@ -0,0 +1,178 @@
+use crate::args::Args;
+use crate::config::{Configuration, ControlMode, MetadataBackend};
+use crate::control::ControlModule;
+use crate::error::Result;
+use crate::metadata::Metadata;
+use crate::storage::Storage;
+
+/// Programs the controller runs on the storage server
+const CONTROLLER_TOOLS: &[&str] = &["zfs", "targetcli"];
+/// Programs the node runs to attach and mount volumes
+const NODE_TOOLS: &[&str] = &[
+    "iscsiadm",
+    "findmnt",
+    "lsblk",
+    "mount",
+    "umount",
+    "mkfs.ext4",
+];
+/// Only needed by volumes that ask for these filesystems
+const OPTIONAL_NODE_TOOLS: &[&str] = &["mkfs.ext2", "mkfs.ext3", "mkfs.xfs"];
+
+/// Problems found in the configuration, errors keep the driver from becoming ready
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+struct Target<'a> {
+    name: String,
+    mode: &'a ControlMode,
+    tools: &'a [&'a str],
+    optional_tools: &'a [&'a str],
+}
+
+/// Every control target of the configuration, profiles are only included when asked for
+fn targets(config: &Configuration, profiles: bool) -> Vec<Target> {
+    let mut targets = vec![Target {
+        name: "node.control_mode".into(),
+        mode: &config.node.control_mode,
+        tools: NODE_TOOLS,
+        optional_tools: OPTIONAL_NODE_TOOLS,
+    }];
+    if let Some(mode) = &config.controller.control_mode {
+        targets.push(Target {
+            name: "controller.control_mode".into(),
+            mode,
+            tools: CONTROLLER_TOOLS,
+            optional_tools: &[],
+        });
+    }
+    if profiles {
+        for (name, mode) in config.control_profiles.iter() {
+            targets.push(Target {
+                name: format!("control_profiles.{}", name),
+                mode,
+                tools: CONTROLLER_TOOLS,
+                optional_tools: &[],
+            });
+        }
+    }
+    targets
+}
+
+/// Validate the configuration without touching the network
+pub fn validate(config: &Configuration) -> Report {
+    let mut report = Report::default();
+    report.errors.extend(Storage::validate_config(config));
+    if let MetadataBackend::Zfs {
+        control_mode: None, ..
+    } = config.metadata_backend()
+    {
+        report.errors.push(
+            "metadata: the zfs backend needs a control_mode or controller.control_mode".into(),
+        );
+    }
+    for target in targets(config, true) {
+        if let Err(e) = ControlModule::validate(target.mode) {
+            report.errors.push(format!("{}: {}", target.name, e));
+        }
+    }
+    report
+}
+
+/// Validate the configuration, then connect to the control targets and check their tools
+pub async fn check(config: &Configuration, metadata: &Metadata, profiles: bool) -> Report {
+    let mut report = validate(config);
+    for target in targets(config, profiles) {
+        if ControlModule::validate(target.mode).is_err() {
+            continue;
+        }
+        let control = match ControlModule::new(target.mode, metadata) {
+            Ok(c) => c,
+            Err(e) => {
+                report.errors.push(format!("{}: {}", target.name, e));
+                continue;
+            }
+        };
+        if let Err(e) = control.check_access().await {
+            report
+                .errors
+                .push(format!("{}: cannot run commands: {}", target.name, e));
+            continue;
+        }
+        match control.missing_tools(target.tools).await {
+            Ok(missing) => report.errors.extend(
+                missing
+                    .iter()
+                    .map(|t| format!("{}: '{}' was not found", target.name, t)),
+            ),
+            Err(e) => report.errors.push(format!("{}: {}", target.name, e)),
+        }
+        match control.missing_tools(target.optional_tools).await {
+            Ok(missing) => report.warnings.extend(
+                missing
+                    .iter()
+                    .map(|t| format!("{}: '{}' was not found", target.name, t)),
+            ),
+            Err(e) => report.warnings.push(format!("{}: {}", target.name, e)),
+        }
+    }
+    report
+}
+
+/// The `check-config` subcommand, prints every problem found and returns whether the
+/// configuration can be used
+pub async fn check_config(args: &Args, connect: bool) -> Result<bool> {
+    let config = Configuration::new(args)?;
+    let report = if connect {
+        let metadata =
+            match Metadata::new(&config.metadata_backend(), &args.metadata_db, false).await {
+                Ok(m) => m,
+                Err(e) => {
+                    // The driver holds the lock while it runs, known host keys won't be available
+                    warn!("Could not open metadata db ({}), using a temporary one", e);
+                    Metadata::temporary()?
+                }
+            };
+        check(&config, &metadata, true).await
+    } else {
+        validate(&config)
+    };
+    report
+        .warnings
+        .iter()
+        .for_each(|w| println!("warning: {}", w));
+    report.errors.iter().for_each(|e| println!("error: {}", e));
+    if report.is_ok() {
+        println!("Configuration {} is valid", args.config_path.display());
+    } else {
+        println!(
+            "Configuration {} has {} error(s)",
+            args.config_path.display(),
+            report.errors.len()
+        );
+    }
+    Ok(report.is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn example_configuration_is_valid() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../dist.config.yml");
+        let config = Configuration::load(&path).unwrap();
+        let report = validate(&config);
+        assert!(report.is_ok(), "{:?}", report.errors);
+    }
+}
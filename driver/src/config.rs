@@ -1,26 +1,249 @@
 use crate::args::Args;
+use crate::error::AppError;
 use crate::Result;
-use std::fs::File;
-use std::io::BufReader;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 #[derive(Debug, Deref, Default, Clone)]
 pub struct Configuration(Arc<InnerConfiguration>);
 
 #[derive(Debug, Default, Serialize, Deserialize)]
-#[serde(default, rename_all = "snake_case")]
+#[serde(default, deny_unknown_fields, rename_all = "snake_case")]
 pub struct InnerConfiguration {
     pub node: NodeOptions,
+    pub controller: ControllerOptions,
+    pub driver: DriverOptions,
+    pub iscsi: ISCSIDefaults,
+    pub zfs: ZFSDefaults,
+    pub timeouts: TimeoutOptions,
+    /// Control modes that StorageClass secrets can refer to with `controlProfile: <name>`
+    pub control_profiles: HashMap<String, ControlMode>,
+    pub metadata: MetadataBackend,
+    pub gc: GcOptions,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
-#[serde(default, rename_all = "snake_case")]
+#[serde(default, deny_unknown_fields, rename_all = "snake_case")]
 pub struct NodeOptions {
+    pub initiator_iqn_mode: InitiatorIqnMode,
     pub control_mode: ControlMode,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+/// Where the node finds the IQN its initiator logs in with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", deny_unknown_fields, rename_all = "snake_case")]
+pub enum InitiatorIqnMode {
+    /// Use the IQN open-iscsi has in `/etc/iscsi/initiatorname.iscsi`
+    Detect,
+    Fixed {
+        iqn: String,
+    },
+}
+
+impl Default for InitiatorIqnMode {
+    fn default() -> Self {
+        Self::Detect
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "snake_case")]
+pub struct ControllerOptions {
+    /// Used to reach the storage server outside of a request, e.g. to reconcile targets on
+    /// startup, and by StorageClasses whose secrets don't configure a control mode
+    pub control_mode: Option<ControlMode>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "snake_case")]
+pub struct DriverOptions {
+    /// Name the driver registers with when `--csi-name` isn't given
+    pub name: Option<String>,
+    /// What happens to the dataset of a deleted volume, `reclaimPolicy` in a StorageClass
+    pub reclaim_policy: ReclaimPolicy,
+    /// Storage type used when a StorageClass doesn't set `type`, e.g. `iscsi` or `nfs`
+    pub storage_class: Option<String>,
+    /// Whether deployment tooling should mark the generated StorageClass as the default
+    pub default_class: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReclaimPolicy {
+    /// Keep the dataset, it has to be cleaned up by hand
+    Retain,
+    /// Destroy the dataset along with the volume
+    Delete,
+}
+
+impl Default for ReclaimPolicy {
+    fn default() -> Self {
+        Self::Retain
+    }
+}
+
+impl ReclaimPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReclaimPolicy::Retain => "retain",
+            ReclaimPolicy::Delete => "delete",
+        }
+    }
+}
+
+impl std::str::FromStr for ReclaimPolicy {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "retain" => Ok(ReclaimPolicy::Retain),
+            "delete" => Ok(ReclaimPolicy::Delete),
+            _ => Err(AppError::Generic(format!(
+                "'{}' is not a reclaim policy, expected retain or delete",
+                s
+            ))),
+        }
+    }
+}
+
+/// Defaults for the iSCSI parameters of StorageClasses
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "snake_case")]
+pub struct ISCSIDefaults {
+    pub base_iqn: Option<String>,
+    pub target_portal: Option<String>,
+    /// TPG attributes, `attr.<name>` in a StorageClass
+    pub attributes: HashMap<String, String>,
+    /// Backstore attributes, `backstore.attr.<name>` in a StorageClass
+    pub backstore_attributes: HashMap<String, String>,
+}
+
+/// Defaults for the ZFS parameters of StorageClasses
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "snake_case")]
+pub struct ZFSDefaults {
+    pub parent_dataset: Option<String>,
+    /// Dataset properties, `zfs.attr.<name>` in a StorageClass
+    pub attributes: HashMap<String, String>,
+}
+
+/// Periodic removal of datasets, LIO objects and metadata records that no volume uses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "snake_case")]
+pub struct GcOptions {
+    /// Run the collector on the controller
+    pub enabled: bool,
+    /// Seconds between runs
+    pub interval: u64,
+    /// Seconds an orphan must have been seen for before it is deleted or archived
+    pub grace_period: u64,
+    pub action: GcAction,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            enabled: false,
+            interval: 3600,
+            grace_period: 7 * 24 * 3600,
+            action: GcAction::Report,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GcAction {
+    /// Only log the orphans found
+    Report,
+    Delete,
+    /// Move orphaned datasets aside instead of destroying them, LIO objects and records are deleted
+    Archive,
+}
+
+impl Default for GcAction {
+    fn default() -> Self {
+        Self::Report
+    }
+}
+
+/// Where volume metadata is kept
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", deny_unknown_fields, rename_all = "snake_case")]
+pub enum MetadataBackend {
+    /// An embedded sled database at `path`, or `--metadata-db` if unset
+    Sled {
+        #[serde(default)]
+        path: Option<PathBuf>,
+    },
+    /// A JSON file rewritten atomically on every change, for small installs
+    File { path: PathBuf },
+    /// User properties of a dataset on the storage server, so the state lives with the data
+    Zfs {
+        dataset: String,
+        /// Defaults to the controller control mode
+        #[serde(default)]
+        control_mode: Option<ControlMode>,
+    },
+}
+
+impl Default for MetadataBackend {
+    fn default() -> Self {
+        Self::Sled { path: None }
+    }
+}
+
+impl std::str::FromStr for MetadataBackend {
+    type Err = AppError;
+
+    /// Parse `sled[:path]`, `file:path` or `zfs:dataset`, as given on the command line
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, arg) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        match (kind, arg) {
+            ("sled", path) => Ok(Self::Sled {
+                path: path.map(PathBuf::from),
+            }),
+            ("file", Some(path)) => Ok(Self::File { path: path.into() }),
+            ("zfs", Some(dataset)) => Ok(Self::Zfs {
+                dataset: dataset.to_string(),
+                control_mode: None,
+            }),
+            _ => Err(AppError::InvalidArgument(format!(
+                "'{}' is not a metadata backend, use sled[:path], file:path or zfs:dataset",
+                s
+            ))),
+        }
+    }
+}
+
+/// Limits on how long commands run on the node or storage server may take, in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "snake_case")]
+pub struct TimeoutOptions {
+    /// Applied to every command without a timeout for its class
+    pub default: u64,
+    /// Timeouts by command class, the program name up to the first `.`, e.g. `mkfs` or `zfs`
+    pub commands: HashMap<String, u64>,
+}
+
+impl Default for TimeoutOptions {
+    fn default() -> Self {
+        let mut commands = HashMap::new();
+        commands.insert("mkfs".to_string(), 600);
+        TimeoutOptions {
+            default: 120,
+            commands,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", deny_unknown_fields, rename_all = "snake_case")]
 pub enum ControlMode {
     Local {
         #[serde(default)]
@@ -34,6 +257,17 @@ pub enum ControlMode {
         path: String,
     },
 
+    /// Run commands in the namespaces of a host process, for node plugins in slim containers
+    Nsenter {
+        #[serde(default)]
+        sudo: bool,
+        /// Process whose namespaces are entered, PID 1 of the host with `hostPID: true`
+        #[serde(default = "Namespace::default_target_pid")]
+        target_pid: u32,
+        #[serde(default = "Namespace::defaults")]
+        namespaces: Vec<Namespace>,
+    },
+
     #[serde(rename = "ssh")]
     SSH {
         #[serde(default)]
@@ -42,13 +276,81 @@ pub enum ControlMode {
         user: String,
         #[serde(default)]
         private_key: String,
+        /// Path to a private key file, used instead of the inline key
+        #[serde(default)]
+        private_key_file: Option<String>,
+        /// Passphrase of an encrypted private key
+        #[serde(default)]
+        passphrase: Option<String>,
+        /// Authenticate with the keys of the ssh-agent at `SSH_AUTH_SOCK`
+        #[serde(default)]
+        agent: bool,
+        /// Authenticate with the keys of the ssh-agent at this socket
+        #[serde(default)]
+        agent_socket: Option<String>,
+        /// Password authentication, for appliances that don't allow keys. Only the `password`
+        /// method is used, servers that only offer `keyboard-interactive` need
+        /// `PasswordAuthentication yes` in their sshd_config
+        #[serde(default)]
+        password: Option<String>,
         #[serde(default)]
         host: String,
         #[serde(default)]
         port: u16,
+        /// Path to an OpenSSH known_hosts file used to verify the server
+        #[serde(default)]
+        known_hosts_file: Option<String>,
+        /// Pinned server key, either an OpenSSH public key line or a `SHA256:` fingerprint
+        #[serde(default)]
+        host_key: Option<String>,
+        /// Record the first key seen and reject any other key afterwards
+        #[serde(default)]
+        trust_on_first_use: bool,
     },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Namespace {
+    Mount,
+    Uts,
+    Ipc,
+    Net,
+    Pid,
+    Cgroup,
+    User,
+}
+
+impl Namespace {
+    fn default_target_pid() -> u32 {
+        1
+    }
+
+    /// The mount namespace is what makes mounts visible to the kubelet, the others give
+    /// iscsiadm the host's network and hostname
+    pub fn defaults() -> Vec<Namespace> {
+        vec![
+            Namespace::Mount,
+            Namespace::Uts,
+            Namespace::Ipc,
+            Namespace::Net,
+        ]
+    }
+
+    /// The nsenter option that enters this namespace
+    pub fn flag(&self) -> &'static str {
+        match self {
+            Namespace::Mount => "--mount",
+            Namespace::Uts => "--uts",
+            Namespace::Ipc => "--ipc",
+            Namespace::Net => "--net",
+            Namespace::Pid => "--pid",
+            Namespace::Cgroup => "--cgroup",
+            Namespace::User => "--user",
+        }
+    }
+}
+
 impl Default for ControlMode {
     fn default() -> Self {
         Self::Local { sudo: false }
@@ -56,10 +358,324 @@ impl Default for ControlMode {
 }
 
 impl Configuration {
-    pub fn new(args: Args) -> Result<Self> {
-        let file = File::open(args.config_path)?;
-        let reader = BufReader::new(file);
-        let res = serde_yaml::from_reader(reader)?;
+    pub fn new(args: &Args) -> Result<Self> {
+        Self::load(&args.config_path)
+    }
+
+    /// Load the configuration file, apply the `METAL_CSI_CFG__*` environment overrides and
+    /// resolve `file:` and `env:` references in string values
+    pub fn load(path: &Path) -> Result<Self> {
+        let invalid = |e: serde_yaml::Error| {
+            AppError::Generic(format!(
+                "Invalid configuration in {}: {}",
+                path.display(),
+                e
+            ))
+        };
+        let text = std::fs::read_to_string(path)?;
+        let mut value = match serde_yaml::from_str(&text).map_err(invalid)? {
+            Value::Null => Value::Mapping(Mapping::new()),
+            value => value,
+        };
+        apply_env_overrides(&mut value, std::env::vars())?;
+        resolve_references(&mut value)?;
+        let res = serde_yaml::from_value(value).map_err(invalid)?;
         Ok(Self(Arc::new(res)))
     }
+
+    /// The configured metadata backend
+    pub fn metadata_backend(&self) -> MetadataBackend {
+        self.resolve_metadata_backend(&self.metadata)
+    }
+
+    /// Fill in the control mode of a zfs backend from the controller's
+    pub fn resolve_metadata_backend(&self, backend: &MetadataBackend) -> MetadataBackend {
+        match backend {
+            MetadataBackend::Zfs {
+                dataset,
+                control_mode: None,
+            } => MetadataBackend::Zfs {
+                dataset: dataset.to_string(),
+                control_mode: self.controller.control_mode.clone(),
+            },
+            backend => backend.clone(),
+        }
+    }
+
+    /// Every control mode defined in the configuration
+    pub fn control_modes(&self) -> Vec<&ControlMode> {
+        std::iter::once(&self.node.control_mode)
+            .chain(self.controller.control_mode.as_ref())
+            .chain(self.control_profiles.values())
+            .collect()
+    }
+
+    /// StorageClass parameters with the configured defaults filled in for the keys they don't set
+    pub fn parameters(&self, params: &HashMap<String, String>) -> HashMap<String, String> {
+        let mut result = HashMap::new();
+        if let Some(storage_type) = &self.driver.storage_class {
+            result.insert("type".to_string(), storage_type.to_string());
+        }
+        result.insert(
+            "reclaimPolicy".to_string(),
+            self.driver.reclaim_policy.as_str().to_string(),
+        );
+        if let Some(base_iqn) = &self.iscsi.base_iqn {
+            result.insert("baseIqn".to_string(), base_iqn.to_string());
+        }
+        if let Some(target_portal) = &self.iscsi.target_portal {
+            result.insert("targetPortal".to_string(), target_portal.to_string());
+        }
+        for (k, v) in self.iscsi.attributes.iter() {
+            result.insert(format!("attr.{}", k), v.to_string());
+        }
+        for (k, v) in self.iscsi.backstore_attributes.iter() {
+            result.insert(format!("backstore.attr.{}", k), v.to_string());
+        }
+        if let Some(parent_dataset) = &self.zfs.parent_dataset {
+            result.insert("zfs.parentDataset".to_string(), parent_dataset.to_string());
+        }
+        for (k, v) in self.zfs.attributes.iter() {
+            result.insert(format!("zfs.attr.{}", k), v.to_string());
+        }
+        result.extend(params.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        result
+    }
+}
+
+/// Environment variables starting with this prefix override configuration keys
+pub const ENV_PREFIX: &str = "METAL_CSI_CFG__";
+/// Separates the levels of a key in an environment variable name, single underscores are
+/// part of the key names
+const ENV_SEPARATOR: &str = "__";
+
+/// Set configuration keys from environment variables, `METAL_CSI_CFG__CONTROLLER__CONTROL_MODE__HOST`
+/// sets `controller.control_mode.host`. Values are strings unless the key holds a number or a
+/// boolean, values written as YAML mappings or sequences replace whole sections.
+fn apply_env_overrides(
+    config: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    let mut vars: Vec<(String, String)> = vars
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    // Shorter keys first so `..._CONTROL_MODE` is replaced before `..._CONTROL_MODE__HOST` is set
+    vars.sort();
+    // Scalars are set as strings, the ones YAML reads as something else are converted once the
+    // type of their key is known
+    let mut scalars = vec![];
+    for (name, raw) in vars {
+        let path: Vec<String> = name[ENV_PREFIX.len()..]
+            .split(ENV_SEPARATOR)
+            .map(|k| k.to_lowercase())
+            .collect();
+        if path.iter().any(|k| k.is_empty()) {
+            return Err(AppError::Generic(format!(
+                "Invalid configuration override {}, empty key",
+                name
+            )));
+        }
+        *entry(config, &path) = match serde_yaml::from_str(&raw) {
+            Ok(parsed @ Value::Mapping(_)) | Ok(parsed @ Value::Sequence(_)) => parsed,
+            Ok(Value::String(_)) | Err(_) => Value::String(raw),
+            Ok(parsed) => {
+                scalars.push((path.clone(), parsed));
+                Value::String(raw)
+            }
+        };
+        debug!("Configuration key {} set from {}", path.join("."), name);
+    }
+    if scalars.is_empty() {
+        return Ok(());
+    }
+
+    // The configuration with these keys at their defaults tells which of them aren't strings
+    let mut defaults = config.clone();
+    for (path, _) in scalars.iter() {
+        remove(&mut defaults, path);
+    }
+    let defaults = match serde_yaml::from_value::<InnerConfiguration>(defaults)
+        .and_then(|c| serde_yaml::to_value(c))
+    {
+        Ok(defaults) => defaults,
+        // The error is reported once the configuration is read
+        Err(_) => return Ok(()),
+    };
+    for (path, parsed) in scalars {
+        if let Some(Value::Number(_)) | Some(Value::Bool(_)) = lookup(&defaults, &path) {
+            *entry(config, &path) = parsed;
+        }
+    }
+    Ok(())
+}
+
+/// The value at a key, created empty along with the mappings leading to it if it doesn't exist
+fn entry<'a>(config: &'a mut Value, path: &[String]) -> &'a mut Value {
+    let mut node = config;
+    for key in path.iter() {
+        if !node.is_mapping() {
+            *node = Value::Mapping(Mapping::new());
+        }
+        node = node
+            .as_mapping_mut()
+            .unwrap()
+            .entry(Value::String(key.to_string()))
+            .or_insert(Value::Null);
+    }
+    node
+}
+
+/// Remove a key, along with the mappings it leaves empty so that they take their defaults
+fn remove(config: &mut Value, path: &[String]) {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if let Some(map) = config.as_mapping_mut() {
+        let key = Value::String(key.to_string());
+        let empty = match map.get_mut(&key) {
+            Some(_) if rest.is_empty() => true,
+            Some(value) => {
+                remove(value, rest);
+                value.as_mapping().map(|m| m.is_empty()).unwrap_or(false)
+            }
+            None => false,
+        };
+        if empty {
+            map.remove(&key);
+        }
+    }
+}
+
+/// The value at a key. A key missing from a map takes the type of the other entries.
+fn lookup<'a>(config: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let (key, rest) = path.split_first()?;
+    let map = config.as_mapping()?;
+    match map.get(&Value::String(key.to_string())) {
+        Some(value) if rest.is_empty() => Some(value),
+        Some(value) => lookup(value, rest),
+        None if rest.is_empty() => map.iter().next().map(|(_, value)| value),
+        None => None,
+    }
+}
+
+/// Replace `file:/path` strings with the content of the file, without its trailing newline,
+/// and `env:NAME` strings with the value of the environment variable
+fn resolve_references(value: &mut Value) -> Result<()> {
+    match value {
+        Value::String(s) => {
+            if let Some(path) = s.strip_prefix("file:") {
+                let content = std::fs::read_to_string(path).map_err(|e| {
+                    AppError::Generic(format!("Could not read configuration file {}: {}", path, e))
+                })?;
+                *s = content.trim_end_matches(&['\r', '\n'][..]).to_string();
+            } else if let Some(name) = s.strip_prefix("env:") {
+                *s = std::env::var(name).map_err(|e| {
+                    AppError::Generic(format!(
+                        "Could not read configuration variable {}: {}",
+                        name, e
+                    ))
+                })?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (_, v) in map.iter_mut() {
+                resolve_references(v)?;
+            }
+        }
+        Value::Sequence(seq) => {
+            for v in seq.iter_mut() {
+                resolve_references(v)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(yaml: &str, vars: &[(&str, &str)]) -> Result<InnerConfiguration> {
+        let mut value = serde_yaml::from_str(yaml)?;
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        apply_env_overrides(&mut value, vars)?;
+        Ok(serde_yaml::from_value(value)?)
+    }
+
+    #[test]
+    fn converts_values_by_key_type() {
+        let config = load(
+            "gc: {}",
+            &[
+                ("METAL_CSI_CFG__GC__INTERVAL", "60"),
+                ("METAL_CSI_CFG__GC__ENABLED", "true"),
+                ("METAL_CSI_CFG__ISCSI__BASE_IQN", "1234"),
+                ("METAL_CSI_CFG__ISCSI__ATTRIBUTES__AUTHENTICATION", "0"),
+                ("METAL_CSI_CFG__TIMEOUTS__COMMANDS__ZFS", "30"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.gc.interval, 60);
+        assert!(config.gc.enabled);
+        assert_eq!(config.iscsi.base_iqn.as_deref(), Some("1234"));
+        assert_eq!(config.iscsi.attributes["authentication"], "0");
+        assert_eq!(config.timeouts.commands["zfs"], 30);
+    }
+
+    #[test]
+    fn converts_fields_of_tagged_sections() {
+        let config = load(
+            "{}",
+            &[
+                ("METAL_CSI_CFG__CONTROLLER__CONTROL_MODE__TYPE", "ssh"),
+                ("METAL_CSI_CFG__CONTROLLER__CONTROL_MODE__PORT", "2222"),
+                ("METAL_CSI_CFG__CONTROLLER__CONTROL_MODE__PASSWORD", "0123"),
+                ("METAL_CSI_CFG__CONTROLLER__CONTROL_MODE__SUDO", "true"),
+            ],
+        )
+        .unwrap();
+        match config.controller.control_mode {
+            Some(ControlMode::SSH {
+                port,
+                password,
+                sudo,
+                ..
+            }) => {
+                assert_eq!(port, 2222);
+                assert_eq!(password.as_deref(), Some("0123"));
+                assert!(sudo);
+            }
+            other => panic!("control mode {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replaces_sections_with_mappings() {
+        let config = load(
+            "node:\n  control_mode:\n    type: chroot\n    path: /host\n",
+            &[(
+                "METAL_CSI_CFG__NODE__CONTROL_MODE",
+                "{type: local, sudo: true}",
+            )],
+        )
+        .unwrap();
+        assert_eq!(config.node.control_mode, ControlMode::Local { sudo: true });
+    }
+
+    #[test]
+    fn ignores_other_variables() {
+        let config = load(
+            "{}",
+            &[("METAL_CSI_NODE_ID", "node-1"), ("METAL_CSI_LOG", "debug")],
+        )
+        .unwrap();
+        assert!(config.driver.name.is_none());
+    }
+
+    #[test]
+    fn rejects_empty_keys() {
+        assert!(load("{}", &[("METAL_CSI_CFG__GC____INTERVAL", "60")]).is_err());
+    }
 }
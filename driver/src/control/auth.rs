@@ -0,0 +1,103 @@
+use super::ssh::SSHHandler;
+use crate::error::AppError;
+use crate::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Arc;
+use thrussh::client::Handle;
+use thrussh_keys::agent::client::AgentClient;
+use thrussh_keys::key::KeyPair;
+
+/// Credentials used to authenticate an SSH session
+#[derive(Clone)]
+pub enum SSHAuth {
+    /// A private key, decrypted with its passphrase when it was loaded
+    Key(Arc<KeyPair>),
+    /// Keys held by an ssh-agent, at the given socket or `SSH_AUTH_SOCK` if unset
+    Agent(Option<PathBuf>),
+    /// Password authentication for appliances that don't allow keys, keyboard-interactive is
+    /// not supported by the SSH client
+    Password(String),
+}
+
+impl std::fmt::Debug for SSHAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SSHAuth::Key(key) => write!(f, "Key(SHA256:{})", key.clone_public_key().fingerprint()),
+            SSHAuth::Agent(socket) => write!(f, "Agent({:?})", socket),
+            SSHAuth::Password(_) => write!(f, "Password([...])"),
+        }
+    }
+}
+
+impl SSHAuth {
+    pub fn new(
+        private_key: Option<&str>,
+        private_key_file: Option<&str>,
+        passphrase: Option<&str>,
+        agent: bool,
+        agent_socket: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<Self> {
+        let passphrase = passphrase.filter(|p| !p.is_empty());
+        if let Some(path) = private_key_file.filter(|p| !p.is_empty()) {
+            let key = thrussh_keys::load_secret_key(path, passphrase).map_err(|e| {
+                AppError::Generic(format!("Could not load SSH key from {}: {}", path, e))
+            })?;
+            Ok(SSHAuth::Key(Arc::new(key)))
+        } else if let Some(key) = private_key.filter(|k| !k.is_empty()) {
+            let key = thrussh_keys::decode_secret_key(key, passphrase)
+                .map_err(|e| AppError::Generic(format!("Could not decode SSH key: {}", e)))?;
+            Ok(SSHAuth::Key(Arc::new(key)))
+        } else if agent || agent_socket.is_some() {
+            Ok(SSHAuth::Agent(agent_socket.map(PathBuf::from)))
+        } else if let Some(password) = password {
+            Ok(SSHAuth::Password(password.to_string()))
+        } else {
+            Err(AppError::Generic(
+                "No SSH credentials configured, a key, key file, agent or password is required"
+                    .into(),
+            ))
+        }
+    }
+
+    /// Identifies the credentials without exposing them, used to key pooled connections
+    pub fn identity(&self) -> String {
+        match self {
+            SSHAuth::Key(key) => format!("SHA256:{}", key.clone_public_key().fingerprint()),
+            SSHAuth::Agent(socket) => format!("agent:{:?}", socket),
+            SSHAuth::Password(password) => {
+                let mut hasher = DefaultHasher::new();
+                password.hash(&mut hasher);
+                format!("password:{:x}", hasher.finish())
+            }
+        }
+    }
+
+    pub async fn authenticate(&self, session: &mut Handle<SSHHandler>, user: &str) -> Result<bool> {
+        match self {
+            SSHAuth::Key(key) => Ok(session.authenticate_publickey(user, key.clone()).await?),
+            SSHAuth::Password(password) => Ok(session
+                .authenticate_password(user, password.as_str())
+                .await?),
+            SSHAuth::Agent(socket) => {
+                let mut agent = match socket {
+                    Some(path) => AgentClient::connect_uds(path).await?,
+                    None => AgentClient::connect_env().await?,
+                };
+                let identities = agent.request_identities().await?;
+                debug!("ssh-agent offered {} identities", identities.len());
+                for identity in identities {
+                    let (returned, result) =
+                        session.authenticate_future(user, identity, agent).await;
+                    agent = returned;
+                    if result? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
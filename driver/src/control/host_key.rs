@@ -0,0 +1,158 @@
+use crate::metadata::{Metadata, Storeable};
+use crate::Result;
+use std::path::PathBuf;
+use thrussh_keys::key::PublicKey;
+
+/// How the identity of an SSH server is verified before any command is sent to it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct HostKeyPolicy {
+    /// Path to an OpenSSH known_hosts file
+    pub known_hosts_file: Option<PathBuf>,
+    /// Pinned keys, either OpenSSH public key lines or `SHA256:` fingerprints
+    pub host_keys: Vec<String>,
+    /// Record the first key seen in the metadata database and require it afterwards
+    pub trust_on_first_use: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HostKeyCheck {
+    Trusted,
+    Mismatch,
+    Unknown,
+}
+
+/// A host key learned through trust on first use
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedHostKey {
+    pub fingerprint: String,
+}
+
+impl Storeable for TrustedHostKey {
+    const KEY: &'static str = "TrustedHostKey";
+}
+
+impl HostKeyPolicy {
+    pub fn new(
+        known_hosts_file: Option<&str>,
+        host_key: Option<&str>,
+        trust_on_first_use: bool,
+    ) -> Self {
+        HostKeyPolicy {
+            known_hosts_file: known_hosts_file
+                .filter(|p| !p.is_empty())
+                .map(PathBuf::from),
+            host_keys: host_key
+                .map(|k| {
+                    k.lines()
+                        .map(|l| l.trim().to_string())
+                        .filter(|l| !l.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            trust_on_first_use,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known_hosts_file.is_none() && self.host_keys.is_empty() && !self.trust_on_first_use
+    }
+
+    /// Normalize a pinned key or fingerprint into an unpadded base64 SHA256 fingerprint
+    fn pinned_fingerprint(pinned: &str) -> Result<String> {
+        if let Some(fp) = pinned.strip_prefix("SHA256:") {
+            return Ok(fp.trim_end_matches('=').to_string());
+        }
+        // OpenSSH public key line, `<type> <base64> [comment]`, or the bare base64 data
+        let mut fields = pinned.split_whitespace();
+        let first = fields.next().unwrap_or_default();
+        let data = fields.next().unwrap_or(first);
+        Ok(thrussh_keys::parse_public_key_base64(data)?.fingerprint())
+    }
+
+    /// Check the key against the statically configured sources
+    pub fn check(&self, host: &str, port: u16, key: &PublicKey) -> HostKeyCheck {
+        let fingerprint = key.fingerprint();
+        let mut result = HostKeyCheck::Unknown;
+
+        for pinned in self.host_keys.iter() {
+            match Self::pinned_fingerprint(pinned) {
+                Ok(fp) if fp == fingerprint => return HostKeyCheck::Trusted,
+                Ok(_) => result = HostKeyCheck::Mismatch,
+                Err(e) => warn!("Ignoring unparseable pinned host key '{}': {}", pinned, e),
+            }
+        }
+
+        if let Some(path) = &self.known_hosts_file {
+            match thrussh_keys::check_known_hosts_path(host, port, key, path) {
+                Ok(true) => return HostKeyCheck::Trusted,
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("known_hosts check in {} failed: {}", path.display(), e);
+                    result = HostKeyCheck::Mismatch;
+                }
+            }
+        }
+        result
+    }
+
+    /// Verify the key presented by a server, learning it if trust on first use is enabled
+    pub async fn verify(
+        &self,
+        host: &str,
+        port: u16,
+        check: HostKeyCheck,
+        fingerprint: &str,
+        metadata: &Metadata,
+    ) -> bool {
+        match check {
+            HostKeyCheck::Trusted => return true,
+            HostKeyCheck::Mismatch => {
+                error!(
+                    "REJECTED SSH host key SHA256:{} for {}:{}, it does not match the configured host key",
+                    fingerprint, host, port
+                );
+                return false;
+            }
+            HostKeyCheck::Unknown if !self.trust_on_first_use => {
+                error!(
+                    "REJECTED unknown SSH host key SHA256:{} for {}:{}, configure sshHostKey, sshKnownHostsFile or sshTrustOnFirstUse",
+                    fingerprint, host, port
+                );
+                return false;
+            }
+            HostKeyCheck::Unknown => {}
+        }
+
+        let key = format!("{}:{}", host, port);
+        match metadata.get::<TrustedHostKey>(&key).await {
+            Ok(Some(trusted)) if trusted.fingerprint == fingerprint => true,
+            Ok(Some(trusted)) => {
+                error!(
+                    "REJECTED SSH host key SHA256:{} for {}, the key recorded on first use was SHA256:{}",
+                    fingerprint, key, trusted.fingerprint
+                );
+                false
+            }
+            Ok(None) => {
+                warn!(
+                    "Trusting SSH host key SHA256:{} for {} on first use",
+                    fingerprint, key
+                );
+                let trusted = TrustedHostKey {
+                    fingerprint: fingerprint.to_string(),
+                };
+                match metadata.set(&key, trusted).await {
+                    Ok(_) => true,
+                    Err(e) => {
+                        error!("Failed to record SSH host key for {}: {}", key, e);
+                        false
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to look up SSH host key for {}: {}", key, e);
+                false
+            }
+        }
+    }
+}
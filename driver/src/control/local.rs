@@ -1,14 +1,134 @@
 use super::*;
+use crate::config::Namespace;
 use std::process::Stdio;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     process::{Child, ChildStderr, ChildStdout, Command},
+    time::{self, Instant},
 };
 
 #[derive(Debug)]
 pub struct LocalShell {
     pub sudo: bool,
     pub chroot: Option<String>,
+    pub nsenter: Option<Nsenter>,
+}
+
+/// Namespaces of another process, usually the host's init, that commands are run in
+#[derive(Debug)]
+pub struct Nsenter {
+    pub target_pid: u32,
+    pub namespaces: Vec<Namespace>,
+}
+
+/// Start the command in a process group of its own, so that a timeout can end everything it
+/// started and not only the shell or sudo in front of it
+fn own_process_group(command: &mut Command) -> &mut Command {
+    // setpgid is async-signal-safe, it may run between fork and exec
+    unsafe {
+        command.pre_exec(|| match libc::setpgid(0, 0) {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        })
+    }
+}
+
+/// Kill every process of the group the child leads. Commands run with sudo belong to root and
+/// can't be signalled by the driver, their group is killed through sudo as well.
+async fn kill_group(pgid: Option<u32>, sudo: bool) {
+    let pgid = match pgid {
+        Some(pgid) => pgid,
+        None => return,
+    };
+    if unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) } != 0 && !sudo {
+        warn!(
+            "Could not kill process group {}: {}",
+            pgid,
+            std::io::Error::last_os_error()
+        );
+    }
+    if sudo {
+        let group = format!("-{}", pgid);
+        match Command::new("sudo")
+            .args(&["-n", "kill", "-KILL", "--", &group])
+            .output()
+            .await
+        {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => warn!(
+                "Could not kill process group {}: {}",
+                pgid,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => warn!("Could not kill process group {}: {}", pgid, e),
+        }
+    }
+}
+
+impl LocalShell {
+    /// The words placed before every command to run it with sudo, in the chroot or in the
+    /// namespaces of the target process
+    fn prefix(&self) -> Vec<String> {
+        let mut argv = vec![];
+        if self.sudo {
+            argv.push("sudo".to_string());
+            argv.push("-n".to_string());
+        }
+        if let Some(path) = &self.chroot {
+            argv.push("chroot".to_string());
+            argv.push(path.to_string());
+        }
+        if let Some(nsenter) = &self.nsenter {
+            argv.push("nsenter".to_string());
+            argv.push(format!("--target={}", nsenter.target_pid));
+            argv.extend(nsenter.namespaces.iter().map(|ns| ns.flag().to_string()));
+        }
+        argv
+    }
+
+    /// A shell running the command line behind the prefix, and the line for messages
+    fn command_line(&self, cmd: &str) -> (Command, String) {
+        let prefix = self.prefix();
+        let words: Vec<&str> = prefix.iter().map(|w| w.as_str()).collect();
+        let line = shell_command(&words, cmd);
+        let mut command = Command::new("sh");
+        command.args(&["-c", &line]);
+        (command, line)
+    }
+
+    /// Run the command to completion, its process group is killed when it times out
+    async fn output(
+        &self,
+        mut command: Command,
+        cmd: &str,
+        timeout: Duration,
+    ) -> Result<CommandOutput> {
+        let started = Instant::now();
+        let child = own_process_group(&mut command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let pgid = child.id();
+        let result = match time::timeout(timeout, child.wait_with_output()).await {
+            Ok(result) => result?,
+            Err(_) => {
+                kill_group(pgid, self.sudo).await;
+                return Err(AppError::Timeout {
+                    command: cmd.to_string(),
+                    timeout,
+                });
+            }
+        };
+
+        Ok(CommandOutput {
+            stdout: String::from_utf8(result.stdout)?,
+            stderr: String::from_utf8(result.stderr)?,
+            code: result.status.code().unwrap_or(256) as u32,
+            duration: started.elapsed(),
+        })
+    }
 }
 
 #[async_trait]
@@ -21,31 +141,32 @@ impl ControlModuleTrait for LocalShell {
         Ok(())
     }
 
-    async fn exec(&self, cmd: &str) -> Result<(String, u32)> {
-        let cmd = self.build_command(self.sudo, self.chroot.as_ref().map(|s| s.as_str()), cmd);
-        let result = Command::new("sh")
-            .args(&["-c", &cmd])
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .kill_on_drop(true)
-            .output()
-            .await?;
+    async fn exec_timeout(&self, cmd: &str, timeout: Duration) -> Result<CommandOutput> {
+        let (command, cmd) = self.command_line(cmd);
+        self.output(command, &cmd, clamp(timeout)).await
+    }
 
-        Ok((
-            format!(
-                "{}\n{}",
-                std::str::from_utf8(&result.stdout)?.trim_end(),
-                std::str::from_utf8(&result.stderr)?.trim_end(),
-            ),
-            result.status.code().unwrap_or(256) as u32,
-        ))
+    async fn exec_argv_timeout(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<CommandOutput> {
+        let mut argv = self.prefix();
+        argv.push("env".to_string());
+        argv.push(LOCALE_ENV.to_string());
+        let mut command = Command::new(&argv[0]);
+        command.args(&argv[1..]).arg(program).args(args);
+        self.output(command, &shell_join(program, args), clamp(timeout))
+            .await
     }
 
-    async fn exec_open(&self, cmd: &str) -> Result<ControlStream> {
-        let cmd = self.build_command(self.sudo, self.chroot.as_ref().map(|s| s.as_str()), cmd);
-        let mut child = Command::new("sh")
-            .args(&["-c", &cmd])
+    async fn exec_open_timeout(&self, cmd: &str, timeout: Duration) -> Result<ControlStream> {
+        let started = Instant::now();
+        let timeout = clamp(timeout);
+        let deadline = started + timeout;
+        let (mut command, cmd) = self.command_line(cmd);
+        let mut child = own_process_group(&mut command)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -59,6 +180,11 @@ impl ControlModuleTrait for LocalShell {
             child: Some(child),
             stdout,
             stderr,
+            command: cmd,
+            sudo: self.sudo,
+            timeout,
+            started,
+            deadline,
         })))
     }
 
@@ -72,21 +198,53 @@ pub struct LocalShellStream {
     child: Option<Child>,
     stdout: BufReader<ChildStdout>,
     stderr: BufReader<ChildStderr>,
+    command: String,
+    sudo: bool,
+    timeout: Duration,
+    started: Instant,
+    deadline: Instant,
+}
+
+impl LocalShellStream {
+    fn timed_out(&self) -> AppError {
+        AppError::Timeout {
+            command: self.command.clone(),
+            timeout: self.timeout,
+        }
+    }
 }
 
 #[async_trait]
 impl ControlStreamTrait for LocalShellStream {
-    async fn wait_for_completion(&mut self) -> Result<(String, u32)> {
-        if let Some(child) = self.child.take() {
-            let result = child.wait_with_output().await?;
-            Ok((
-                format!(
-                    "{}\n{}",
-                    std::str::from_utf8(&result.stdout)?.trim_end(),
-                    std::str::from_utf8(&result.stderr)?.trim_end(),
-                ),
-                result.status.code().unwrap_or(256) as u32,
-            ))
+    async fn wait_for_completion(&mut self) -> Result<CommandOutput> {
+        if let Some(mut child) = self.child.take() {
+            let deadline = self.deadline;
+            let mut stdout = String::new();
+            let mut stderr = String::new();
+            let read = async {
+                let (out, err, status) = tokio::join!(
+                    self.stdout.read_to_string(&mut stdout),
+                    self.stderr.read_to_string(&mut stderr),
+                    child.wait()
+                );
+                out?;
+                err?;
+                Ok::<_, AppError>(status?)
+            };
+            let result = time::timeout_at(deadline, read).await;
+            let status = match result {
+                Ok(status) => status?,
+                Err(_) => {
+                    kill_group(child.id(), self.sudo).await;
+                    return Err(self.timed_out());
+                }
+            };
+            Ok(CommandOutput {
+                stdout,
+                stderr,
+                code: status.code().unwrap_or(256) as u32,
+                duration: self.started.elapsed(),
+            })
         } else {
             Err(AppError::Generic(format!(
                 "This stream has been completed!"
@@ -95,36 +253,54 @@ impl ControlStreamTrait for LocalShellStream {
     }
 
     async fn wait_for(&mut self, ptrn: &Regex) -> Result<(String, Option<u32>)> {
-        if let Some(child) = &mut self.child {
-            let mut output = String::new();
-            let mut found = false;
-            let mut code = None;
-            while !found && code.is_none() {
-                let mut stdout_line = Default::default();
-                let mut stderr_line = Default::default();
-                tokio::select! {
-                    val = self.stdout.read_line(&mut stdout_line) => {
-                        if val? == 0 {
-                            continue;
+        let LocalShellStream {
+            child,
+            stdout,
+            stderr,
+            sudo,
+            deadline,
+            ..
+        } = &mut *self;
+        if let Some(child) = child {
+            let read = async {
+                let mut output = String::new();
+                let mut found = false;
+                let mut code = None;
+                while !found && code.is_none() {
+                    let mut stdout_line = Default::default();
+                    let mut stderr_line = Default::default();
+                    tokio::select! {
+                        val = stdout.read_line(&mut stdout_line) => {
+                            if val? == 0 {
+                                continue;
+                            }
                         }
-                    }
-                    val = self.stderr.read_line(&mut stderr_line) => {
-                        if val? == 0 {
-                            continue;
+                        val = stderr.read_line(&mut stderr_line) => {
+                            if val? == 0 {
+                                continue;
+                            }
+                        }
+                        val = child.wait() => {
+                            code = val?.code().map(|v| v as u32);
                         }
                     }
-                    val = child.wait() => {
-                        code = val?.code().map(|v| v as u32);
+                    debug!("{}{}", stdout_line, stderr_line);
+                    if ptrn.is_match(&stdout_line) || ptrn.is_match(&stderr_line) {
+                        found = true;
                     }
+                    output.push_str(&stdout_line);
+                    output.push_str(&stderr_line);
                 }
-                debug!("{}{}", stdout_line, stderr_line);
-                if ptrn.is_match(&stdout_line) || ptrn.is_match(&stderr_line) {
-                    found = true;
+                Ok::<_, AppError>((output, code))
+            };
+            let result = time::timeout_at(*deadline, read).await;
+            match result {
+                Ok(result) => result,
+                Err(_) => {
+                    kill_group(child.id(), *sudo).await;
+                    Err(self.timed_out())
                 }
-                output.push_str(&stdout_line);
-                output.push_str(&stderr_line);
             }
-            Ok((output, code))
         } else {
             Err(AppError::Generic("Child process unavailable!".into()))
         }
@@ -1,28 +1,29 @@
-use crate::config::ControlMode;
-use crate::control::local::LocalShell;
+use crate::config::{Configuration, ControlMode, Namespace};
+use crate::control::local::{LocalShell, Nsenter};
 use crate::error::AppError;
+use crate::metadata::Metadata;
 use crate::Result;
 use async_trait::async_trait;
+pub use auth::*;
+pub use host_key::*;
 use regex::Regex;
 use ssh::*;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::sync::Arc;
+pub use timeout::*;
+use tokio::time::Duration;
 
+mod auth;
+mod host_key;
 mod local;
 mod ssh;
+mod timeout;
 
 #[derive(Debug, Clone, Deref, DerefMut)]
 pub struct ControlModule(Arc<Box<dyn ControlModuleTrait>>);
 
-impl Drop for ControlModule {
-    fn drop(&mut self) {
-        let handle = tokio::runtime::Handle::current();
-        let _enter = handle.enter();
-        futures::executor::block_on(self.disconnect()).unwrap_or_default();
-    }
-}
-
 #[derive(Debug, Deref, DerefMut)]
 pub struct ControlStream(Box<dyn ControlStreamTrait>);
 
@@ -30,95 +31,432 @@ pub struct ControlStream(Box<dyn ControlStreamTrait>);
 pub trait ControlModuleTrait: Send + Sync + Debug {
     async fn is_connected(&self) -> Result<bool>;
     async fn connect(&self) -> Result<()>;
-    async fn exec(&self, cmd: &str) -> Result<(String, u32)>;
-    async fn exec_open(&self, cmd: &str) -> Result<ControlStream>;
+    /// Run a command, killing it once the timeout or the deadline of the request expires
+    async fn exec_timeout(&self, cmd: &str, timeout: Duration) -> Result<CommandOutput>;
+    /// Start an interactive command, the timeout covers the whole lifetime of the stream
+    async fn exec_open_timeout(&self, cmd: &str, timeout: Duration) -> Result<ControlStream>;
     async fn disconnect(&self) -> Result<()>;
 
+    async fn exec(&self, cmd: &str) -> Result<CommandOutput> {
+        self.exec_timeout(cmd, timeout_for(cmd)).await
+    }
+
+    async fn exec_open(&self, cmd: &str) -> Result<ControlStream> {
+        self.exec_open_timeout(cmd, timeout_for(cmd)).await
+    }
+
     fn build_command(&self, sudo: bool, chroot: Option<&str>, cmd: &str) -> String {
-        let prefix_str = if sudo { "sudo " } else { "" };
-        let prefix_str = if let Some(path) = chroot {
-            format!("{}chroot {} ", prefix_str, path)
-        } else {
-            format!("{}", prefix_str)
-        };
-        format!("{}{}", prefix_str, cmd)
+        let mut prefix = vec![];
+        if sudo {
+            prefix.extend(&["sudo", "-n"]);
+        }
+        if let Some(path) = chroot {
+            prefix.extend(&["chroot", path]);
+        }
+        shell_command(&prefix, cmd)
     }
 
+    /// Run a command, returning its stdout if it exited successfully
     async fn exec_checked(&self, cmd: &str) -> Result<String> {
-        let (output, code) = self.exec(cmd).await?;
-        if code != 0 {
-            Err(AppError::CommandFailed { code, output })
+        Ok(self.exec(cmd).await?.check(cmd)?.stdout)
+    }
+
+    /// Run a program with an argument vector, no argument is ever interpreted by a shell
+    async fn exec_argv(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        self.exec_argv_timeout(program, args, timeout_for(program))
+            .await
+    }
+
+    async fn exec_argv_timeout(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<CommandOutput> {
+        self.exec_timeout(&shell_join(program, args), timeout).await
+    }
+
+    async fn exec_argv_checked(&self, program: &str, args: &[&str]) -> Result<String> {
+        let output = self.exec_argv(program, args).await?;
+        Ok(output.check(&shell_join(program, args))?.stdout)
+    }
+}
+
+/// Commands run in the C locale so that their output parses the same on every host
+pub const LOCALE_ENV: &str = "LC_ALL=C";
+/// Sets the locale for every command of a command line, `env` only covers the first one
+const LOCALE_SCRIPT: &str = "LC_ALL=C; export LC_ALL; ";
+
+/// What a command printed and how it exited
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub code: u32,
+    pub duration: Duration,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == 0
+    }
+
+    /// Turn a non-zero exit code into `AppError::CommandFailed`
+    pub fn check(self, command: &str) -> Result<Self> {
+        if self.success() {
+            Ok(self)
         } else {
-            Ok(output)
+            Err(AppError::CommandFailed {
+                command: command.to_string(),
+                stderr: self.stderr.trim().to_string(),
+                code: self.code,
+            })
+        }
+    }
+}
+
+/// Quote a word for a POSIX shell, words made only of safe characters are left as is
+pub fn shell_quote(word: &str) -> Cow<str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        Cow::Borrowed(word)
+    } else {
+        Cow::Owned(format!("'{}'", word.replace('\'', "'\\''")))
+    }
+}
+
+/// Build a command line from an argument vector that a POSIX shell splits back into the same words
+pub fn shell_join(program: &str, args: &[&str]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().copied())
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Build a command line that runs the shell command line `cmd` in the C locale behind the
+/// prefix words, e.g. sudo. A prefix gets a shell of its own so that it covers every command of
+/// a pipe or list and not only the first one.
+pub fn shell_command(prefix: &[&str], cmd: &str) -> String {
+    let script = format!("{}{}", LOCALE_SCRIPT, cmd);
+    match prefix.split_first() {
+        Some((program, args)) => {
+            let args: Vec<&str> = args
+                .iter()
+                .copied()
+                .chain(vec!["sh", "-c", &script])
+                .collect();
+            shell_join(program, &args)
         }
+        None => script,
     }
 }
 
 #[async_trait]
 pub trait ControlStreamTrait: Send + Sync + Debug {
-    async fn wait_for_completion(&mut self) -> Result<(String, u32)>;
+    async fn wait_for_completion(&mut self) -> Result<CommandOutput>;
     async fn wait_for(&mut self, ptrn: &Regex) -> Result<(String, Option<u32>)>;
     async fn sendline(&mut self, data: &str) -> Result<()>;
 }
 
 impl ControlModule {
-    pub fn new(config: &ControlMode) -> Result<ControlModule> {
+    /// Close all pooled connections, this must be called before the runtime stops
+    pub async fn shutdown() -> Result<()> {
+        ssh::shutdown_pool().await
+    }
+
+    /// Drop the pooled connections opened for a control mode, used when its options change
+    pub fn evict(config: &ControlMode) {
+        if let ControlMode::SSH {
+            user, host, port, ..
+        } = config
+        {
+            ssh::evict_pool(user, host, *port);
+        }
+    }
+
+    pub fn new(config: &ControlMode, metadata: &Metadata) -> Result<ControlModule> {
         match config {
             ControlMode::Local { sudo } => Ok(ControlModule(Arc::new(Box::new(LocalShell {
                 sudo: *sudo,
                 chroot: None,
+                nsenter: None,
             })))),
             ControlMode::Chroot { sudo, path } => {
                 Ok(ControlModule(Arc::new(Box::new(LocalShell {
                     sudo: *sudo,
                     chroot: Some(path.to_string()),
+                    nsenter: None,
                 }))))
             }
+            ControlMode::Nsenter {
+                sudo,
+                target_pid,
+                namespaces,
+            } => Ok(ControlModule(Arc::new(Box::new(LocalShell {
+                sudo: *sudo,
+                chroot: None,
+                nsenter: Some(Nsenter {
+                    target_pid: *target_pid,
+                    namespaces: namespaces.clone(),
+                }),
+            })))),
             ControlMode::SSH {
                 sudo,
                 user,
                 private_key,
+                private_key_file,
+                passphrase,
+                agent,
+                agent_socket,
+                password,
                 host,
                 port,
+                known_hosts_file,
+                host_key,
+                trust_on_first_use,
             } => Ok(ControlModule(Arc::new(Box::new(SSHClient::new(
                 user.as_str(),
-                format!("{}:{}", host, port),
-                private_key.to_string(),
+                host.as_str(),
+                *port,
+                SSHAuth::new(
+                    Some(private_key.as_str()),
+                    private_key_file.as_deref(),
+                    passphrase.as_deref(),
+                    *agent,
+                    agent_socket.as_deref(),
+                    password.as_deref(),
+                )?,
                 *sudo,
+                HostKeyPolicy::new(
+                    known_hosts_file.as_deref(),
+                    host_key.as_deref(),
+                    *trust_on_first_use,
+                ),
+                metadata,
             )?)))),
         }
     }
 
-    pub fn from_map(map: &HashMap<String, String>) -> Result<ControlModule> {
+    /// Build a control module from StorageClass secrets, either from a `controlProfile`
+    /// defined in the configuration or from the `type` key and its options. Secrets that
+    /// set neither use the control mode of the controller configuration.
+    pub fn from_map(
+        map: &HashMap<String, String>,
+        config: &Configuration,
+        metadata: &Metadata,
+    ) -> Result<ControlModule> {
+        match map.get("controlProfile") {
+            Some(name) => {
+                let profile = config.control_profiles.get(name).ok_or_else(|| {
+                    AppError::Generic(format!("Control profile '{}' is not configured!", name))
+                })?;
+                Self::new(profile, metadata)
+            }
+            None => match (map.get("type"), &config.controller.control_mode) {
+                (None, Some(control_mode)) => Self::new(control_mode, metadata),
+                _ => Self::new(&Self::control_mode_from_map(map)?, metadata),
+            },
+        }
+    }
+
+    /// Check a control mode without connecting, e.g. that an SSH key can be decoded
+    pub fn validate(config: &ControlMode) -> Result<()> {
+        match config {
+            ControlMode::Local { .. } => Ok(()),
+            ControlMode::Chroot { path, .. } => {
+                if path.is_empty() || !std::path::Path::new(path).is_dir() {
+                    return Err(AppError::Generic(format!(
+                        "chroot path '{}' is not a directory",
+                        path
+                    )));
+                }
+                Ok(())
+            }
+            ControlMode::Nsenter {
+                target_pid,
+                namespaces,
+                ..
+            } => {
+                if *target_pid == 0 || namespaces.is_empty() {
+                    return Err(AppError::Generic(
+                        "nsenter needs a target_pid and at least one namespace".into(),
+                    ));
+                }
+                Ok(())
+            }
+            ControlMode::SSH {
+                user,
+                private_key,
+                private_key_file,
+                passphrase,
+                agent,
+                agent_socket,
+                password,
+                host,
+                port,
+                known_hosts_file,
+                host_key,
+                trust_on_first_use,
+                ..
+            } => {
+                if user.is_empty() || host.is_empty() || *port == 0 {
+                    return Err(AppError::Generic("SSH needs a user, host and port".into()));
+                }
+                SSHAuth::new(
+                    Some(private_key.as_str()),
+                    private_key_file.as_deref(),
+                    passphrase.as_deref(),
+                    *agent,
+                    agent_socket.as_deref(),
+                    password.as_deref(),
+                )?;
+                let policy = HostKeyPolicy::new(
+                    known_hosts_file.as_deref(),
+                    host_key.as_deref(),
+                    *trust_on_first_use,
+                );
+                if policy.is_empty() {
+                    return Err(AppError::Generic(
+                        "SSH needs host_key, known_hosts_file or trust_on_first_use".into(),
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Make sure commands can run, in particular that sudo doesn't ask for a password
+    pub async fn check_access(&self) -> Result<()> {
+        self.connect().await?;
+        self.exec_argv_checked("true", &[]).await?;
+        Ok(())
+    }
+
+    /// The programs from the list that can't be found on the target
+    pub async fn missing_tools(&self, tools: &[&str]) -> Result<Vec<String>> {
+        let mut missing = vec![];
+        for tool in tools {
+            let output = self
+                .exec_argv("sh", &["-c", "command -v \"$1\"", "sh", tool])
+                .await?;
+            if !output.success() {
+                missing.push(tool.to_string());
+            }
+        }
+        Ok(missing)
+    }
+
+    fn control_mode_from_map(map: &HashMap<String, String>) -> Result<ControlMode> {
+        let required = |key: &str| {
+            map.get(key)
+                .map(|v| v.to_string())
+                .ok_or_else(|| AppError::Generic(format!("{} key not found!", key)))
+        };
+        let sudo = match map.get("sudo") {
+            Some(v) => v.parse()?,
+            None => false,
+        };
         match map.get("type").map(|v| v.as_str()) {
-            Some("ssh") => {
-                let user = map
-                    .get("sshUser")
-                    .ok_or_else(|| anyhow::anyhow!("sshUser key not found!"))?;
-                let host = map
-                    .get("sshHost")
-                    .ok_or_else(|| anyhow::anyhow!("sshHost key not found!"))?;
-                let port = map
-                    .get("sshPort")
-                    .ok_or_else(|| anyhow::anyhow!("sshPort key not found!"))?;
-                let private_key = map
+            Some("local") => Ok(ControlMode::Local { sudo }),
+            Some("chroot") => Ok(ControlMode::Chroot {
+                sudo,
+                path: required("chrootPath")?,
+            }),
+            Some("nsenter") => Ok(ControlMode::Nsenter {
+                sudo,
+                target_pid: match map.get("nsenterTargetPid") {
+                    Some(v) => v.parse()?,
+                    None => 1,
+                },
+                namespaces: match map.get("nsenterNamespaces") {
+                    Some(v) => v
+                        .split(',')
+                        .map(|ns| serde_yaml::from_str(ns.trim()))
+                        .collect::<std::result::Result<_, _>>()?,
+                    None => Namespace::defaults(),
+                },
+            }),
+            Some("ssh") => Ok(ControlMode::SSH {
+                sudo: required("sudo")?.parse()?,
+                user: required("sshUser")?,
+                private_key: map
                     .get("sshKey")
-                    .ok_or_else(|| anyhow::anyhow!("sshKey key not found!"))?
-                    .replace("\\n", "\n");
-                let sudo = map
-                    .get("sudo")
-                    .ok_or_else(|| anyhow::anyhow!("sudo key not found!"))?
-                    .parse()?;
-                Ok(ControlModule(Arc::new(Box::new(SSHClient::new(
-                    user.as_str(),
-                    format!("{}:{}", host, port),
-                    private_key.to_string(),
-                    sudo,
-                )?))))
-            }
-            _ => Err(AppError::Generic(format!(
-                "Unknown configuration type for control mode map!"
+                    .map(|k| k.replace("\\n", "\n"))
+                    .unwrap_or_default(),
+                private_key_file: map.get("sshKeyFile").cloned(),
+                passphrase: map.get("sshKeyPassphrase").cloned(),
+                agent: match map.get("sshAgent") {
+                    Some(v) => v.parse()?,
+                    None => false,
+                },
+                agent_socket: map.get("sshAgentSocket").cloned(),
+                password: map.get("sshPassword").cloned(),
+                host: required("sshHost")?,
+                port: required("sshPort")?.parse()?,
+                known_hosts_file: map.get("sshKnownHostsFile").cloned(),
+                host_key: map.get("sshHostKey").map(|s| s.replace("\\n", "\n")),
+                trust_on_first_use: match map.get("sshTrustOnFirstUse") {
+                    Some(v) => v.parse()?,
+                    None => false,
+                },
+            }),
+            Some(t) => Err(AppError::Generic(format!(
+                "'{}' is an unknown control mode type!",
+                t
+            ))),
+            None => Err(AppError::Generic(format!(
+                "Control mode type was not specified!"
             ))),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_only_unsafe_words() {
+        assert_eq!(shell_quote("tank/k8s/pvc-1"), "tank/k8s/pvc-1");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$(reboot)"), "'$(reboot)'");
+        assert_eq!(
+            shell_join("zfs", &["set", "a=b c", "tank"]),
+            "zfs set 'a=b c' tank"
+        );
+    }
+
+    #[test]
+    fn prefixes_cover_compound_commands() {
+        assert_eq!(
+            shell_command(&[], "zfs list | grep tank"),
+            "LC_ALL=C; export LC_ALL; zfs list | grep tank"
+        );
+        let shell = LocalShell {
+            sudo: false,
+            chroot: None,
+            nsenter: None,
+        };
+        assert_eq!(
+            shell.build_command(true, Some("/host root"), "a && b"),
+            "sudo -n chroot '/host root' sh -c 'LC_ALL=C; export LC_ALL; a && b'"
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_every_command_in_the_c_locale() {
+        let shell = LocalShell {
+            sudo: false,
+            chroot: None,
+            nsenter: None,
+        };
+        let output = shell
+            .exec("echo $LC_ALL; sh -c 'echo $LC_ALL' | cat")
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, "C\nC\n");
+    }
+}
@@ -1,128 +1,404 @@
 use super::*;
-use futures::future::{ready, Ready};
+use crate::metadata::Metadata;
+use futures::future::{ready, BoxFuture};
+use futures::FutureExt;
 use std::io::Write;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Once};
 use thrussh::client::*;
 use thrussh::*;
 use thrussh_keys::*;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex as AsyncMutex, RwLock};
+use tokio::time::{self, Duration, Instant};
 
-impl client::Handler for SSHClient {
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// Connections shared across every control module targeting the same host, user, key and
+    /// host key policy
+    static ref POOL: Mutex<HashMap<PoolKey, Arc<SSHConnection>>> = Default::default();
+}
+
+static KEEPALIVE: Once = Once::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    hostname: String,
+    port: u16,
+    user: String,
+    credentials: String,
+    host_key_policy: HostKeyPolicy,
+}
+
+impl std::fmt::Display for PoolKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}:{}", self.user, self.hostname, self.port)
+    }
+}
+
+/// Verifies the server during the handshake of a pooled connection
+#[derive(Clone)]
+pub struct SSHHandler {
+    hostname: String,
+    port: u16,
+    host_key_policy: HostKeyPolicy,
+    metadata: Metadata,
+}
+
+impl client::Handler for SSHHandler {
     type Error = anyhow::Error;
-    type FutureUnit = Ready<anyhow::Result<(Self, client::Session), anyhow::Error>>;
-    type FutureBool = Ready<anyhow::Result<(Self, bool), anyhow::Error>>;
+    type FutureUnit =
+        futures::future::Ready<anyhow::Result<(Self, client::Session), anyhow::Error>>;
+    type FutureBool = BoxFuture<'static, anyhow::Result<(Self, bool), anyhow::Error>>;
 
     fn finished_bool(self, b: bool) -> Self::FutureBool {
-        ready(Ok((self, b)))
+        ready(Ok((self, b))).boxed()
     }
 
     fn finished(self, session: client::Session) -> Self::FutureUnit {
         ready(Ok((self, session)))
     }
 
-    fn check_server_key(self, _: &key::PublicKey) -> Self::FutureBool {
-        self.finished_bool(true)
+    fn check_server_key(self, server_public_key: &key::PublicKey) -> Self::FutureBool {
+        let check = self
+            .host_key_policy
+            .check(&self.hostname, self.port, server_public_key);
+        let fingerprint = server_public_key.fingerprint();
+        async move {
+            let trusted = self
+                .host_key_policy
+                .verify(
+                    &self.hostname,
+                    self.port,
+                    check,
+                    &fingerprint,
+                    &self.metadata,
+                )
+                .await;
+            Ok((self, trusted))
+        }
+        .boxed()
     }
 }
 
-#[derive(Deref, Debug, Clone)]
-pub struct SSHClient(Arc<InnerSSHClient>);
-
-pub struct InnerSSHClient {
-    private_key: String,
-    host: String,
-    user: String,
-    handle: RwLock<Option<Handle<SSHClient>>>,
-    sudo: bool,
+/// A single SSH session, shared through the pool
+pub struct SSHConnection {
+    key: PoolKey,
+    auth: SSHAuth,
+    handler: SSHHandler,
+    /// Opening a channel needs the handle mutably, the outer lock is only held to connect,
+    /// reset or clone the handle so that reconnects never wait for a channel to open
+    handle: RwLock<Option<Arc<AsyncMutex<Handle<SSHHandler>>>>>,
 }
 
-impl std::fmt::Debug for InnerSSHClient {
+impl std::fmt::Debug for SSHConnection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("InnerCSIClient")
-            .field("user", &self.user)
-            .field("private_key", &"[...]")
-            .field("host", &self.host);
+        f.debug_struct("SSHConnection")
+            .field("key", &self.key)
+            .field("auth", &self.auth)
+            .field("host_key_policy", &self.handler.host_key_policy)
+            .finish()
+    }
+}
+
+impl SSHConnection {
+    fn pooled(key: PoolKey, auth: SSHAuth, handler: SSHHandler) -> Arc<Self> {
+        let mut pool = POOL.lock().unwrap();
+        pool.entry(key.clone())
+            .or_insert_with(|| {
+                Arc::new(SSHConnection {
+                    key,
+                    auth,
+                    handler,
+                    handle: RwLock::new(None),
+                })
+            })
+            .clone()
+    }
+
+    async fn is_connected(&self) -> bool {
+        self.handle.read().await.is_some()
+    }
+
+    async fn connect(&self) -> Result<()> {
+        let mut handle = self.handle.write().await;
+        if handle.is_some() {
+            return Ok(());
+        }
+        if self.handler.host_key_policy.is_empty() {
+            return Err(AppError::Generic(format!(
+                "No SSH host key verification configured for {}",
+                self.key
+            )));
+        }
+        let config = Arc::new(thrussh::client::Config::default());
+        let addr = format!("{}:{}", self.key.hostname, self.key.port);
+        let mut session =
+            thrussh::client::connect(config, addr.as_str(), self.handler.clone()).await?;
+
+        let authenticated = self
+            .auth
+            .authenticate(&mut session, self.key.user.as_str())
+            .await?;
+        if !authenticated {
+            return Err(AppError::Generic(format!(
+                "SSH authentication failed for {}",
+                self.key
+            )));
+        }
+
+        info!("Connected to {}", self.key);
+        *handle = Some(Arc::new(AsyncMutex::new(session)));
+        KEEPALIVE.call_once(|| {
+            tokio::spawn(keepalive_task());
+        });
+        Ok(())
+    }
+
+    /// Close the session so the next use reconnects
+    async fn reset(&self) {
+        let handle = self.handle.write().await.take();
+        if let Some(handle) = handle {
+            Self::close(&self.key, handle).await;
+        }
+    }
+
+    /// Disconnect a session that is no longer used, it may be dead already so failures are
+    /// only logged
+    async fn close(key: &PoolKey, handle: Arc<AsyncMutex<Handle<SSHHandler>>>) {
+        let disconnect = async {
+            handle
+                .lock()
+                .await
+                .disconnect(Disconnect::ByApplication, "Client closed", "")
+                .await
+        };
+        match time::timeout(KEEPALIVE_TIMEOUT, disconnect).await {
+            Ok(Ok(_)) => debug!("Disconnected from {}", key),
+            Ok(Err(e)) => debug!("Failed to disconnect from {}: {}", key, e),
+            Err(_) => debug!("Disconnecting from {} timed out", key),
+        }
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        let handle = self.handle.write().await.take();
+        if let Some(handle) = handle {
+            handle
+                .lock()
+                .await
+                .disconnect(Disconnect::ByApplication, "Client closed", "")
+                .await?;
+        }
         Ok(())
     }
+
+    async fn try_exec_channel(&self, cmd: &str) -> Result<Channel> {
+        let handle = match &*self.handle.read().await {
+            Some(handle) => handle.clone(),
+            None => return Err(AppError::Generic("Not connected!".into())),
+        };
+        let mut channel = handle.lock().await.channel_open_session().await?;
+        channel.exec(true, cmd).await?;
+        Ok(channel)
+    }
+
+    /// Start a command, reconnecting and retrying once if the pooled session turns out to be
+    /// dead. The command has not started when opening the channel fails, so the retry is safe.
+    async fn exec_channel(&self, cmd: &str) -> Result<Channel> {
+        self.connect().await?;
+        match self.try_exec_channel(cmd).await {
+            Ok(channel) => Ok(channel),
+            Err(e) => {
+                warn!("SSH session to {} failed ({}), reconnecting", self.key, e);
+                self.reset().await;
+                self.connect().await?;
+                self.try_exec_channel(cmd).await
+            }
+        }
+    }
+
+    /// Run a no-op command to keep the session alive and detect when it has died
+    async fn keepalive(&self) {
+        if !self.is_connected().await {
+            return;
+        }
+        let started = Instant::now();
+        let probe = async {
+            let channel = self.try_exec_channel("true").await?;
+            SSHStream::new(channel, "true", KEEPALIVE_TIMEOUT, started)
+                .wait_for_completion()
+                .await
+        };
+        match time::timeout(KEEPALIVE_TIMEOUT, probe).await {
+            Ok(Ok(_)) => debug!("Keepalive to {} succeeded", self.key),
+            Ok(Err(e)) => {
+                warn!("Keepalive to {} failed, dropping session: {}", self.key, e);
+                self.reset().await;
+            }
+            Err(_) => {
+                warn!("Keepalive to {} timed out, dropping session", self.key);
+                self.reset().await;
+            }
+        }
+    }
+}
+
+impl Drop for SSHConnection {
+    /// A connection is dropped once it left the pool and the last client using it is gone
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.get_mut().take() {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                let key = self.key.clone();
+                runtime.spawn(async move { SSHConnection::close(&key, handle).await });
+            }
+        }
+    }
+}
+
+async fn keepalive_task() {
+    let mut interval = time::interval(KEEPALIVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let connections: Vec<Arc<SSHConnection>> = POOL.lock().unwrap().values().cloned().collect();
+        for connection in connections {
+            connection.keepalive().await;
+        }
+    }
+}
+
+/// Disconnect every pooled session, used on shutdown
+pub async fn shutdown_pool() -> Result<()> {
+    let connections: Vec<Arc<SSHConnection>> =
+        POOL.lock().unwrap().drain().map(|(_, c)| c).collect();
+    for connection in connections {
+        if let Err(e) = connection.disconnect().await {
+            warn!("Failed to disconnect from {}: {}", connection.key, e);
+        }
+    }
+    Ok(())
+}
+
+/// Remove pooled sessions to a server so the next control module opens a new one. Commands
+/// still running keep their session until they complete, it is disconnected after that.
+pub fn evict_pool(user: &str, hostname: &str, port: u16) {
+    POOL.lock().unwrap().retain(|key, _| {
+        let evicted = key.user == user && key.hostname == hostname && key.port == port;
+        if evicted {
+            info!("Dropping pooled session to {}", key);
+        }
+        !evicted
+    });
+}
+
+#[derive(Debug, Clone)]
+pub struct SSHClient {
+    connection: Arc<SSHConnection>,
+    sudo: bool,
 }
 
 impl SSHClient {
-    pub fn new<T: Into<String>, U: Into<String>, V: Into<String>>(
+    pub fn new<T: Into<String>, V: Into<String>>(
         user: V,
-        host: T,
-        private_key: U,
+        hostname: T,
+        port: u16,
+        auth: SSHAuth,
         sudo: bool,
+        host_key_policy: HostKeyPolicy,
+        metadata: &Metadata,
     ) -> Result<Self> {
-        Ok(SSHClient(Arc::new(InnerSSHClient {
-            private_key: private_key.into(),
-            host: host.into(),
+        let hostname = hostname.into();
+        let key = PoolKey {
+            hostname: hostname.clone(),
+            port,
             user: user.into(),
-            handle: RwLock::new(None),
+            credentials: auth.identity(),
+            host_key_policy: host_key_policy.clone(),
+        };
+        let handler = SSHHandler {
+            hostname,
+            port,
+            host_key_policy,
+            metadata: metadata.clone(),
+        };
+        Ok(SSHClient {
+            connection: SSHConnection::pooled(key, auth, handler),
             sudo,
-        })))
+        })
     }
 }
 
 #[async_trait]
 impl ControlModuleTrait for SSHClient {
     async fn connect(&self) -> Result<()> {
-        if self.is_connected().await? {
-            return Ok(());
-        }
-        let config = thrussh::client::Config::default();
-        let config = Arc::new(config);
-
-        let key = Arc::new(thrussh_keys::decode_secret_key(
-            self.private_key.as_str(),
-            None,
-        )?);
-
-        let mut session =
-            thrussh::client::connect(config, self.host.as_str(), self.clone()).await?;
-
-        session
-            .authenticate_publickey(self.user.as_str(), key)
-            .await?;
-
-        *self.handle.write().await = Some(session);
-        Ok(())
+        self.connection.connect().await
     }
-    async fn exec_open(&self, cmd: &str) -> Result<ControlStream> {
-        let mut channel = if let Some(handle) = &mut *self.handle.write().await {
-            handle.channel_open_session().await?
-        } else {
-            return Err(AppError::Generic("Not connected!".into()));
-        };
+
+    async fn exec_open_timeout(&self, cmd: &str, timeout: Duration) -> Result<ControlStream> {
+        let timeout = clamp(timeout);
         let cmd = self.build_command(self.sudo, None, cmd);
-        channel.exec(true, &cmd).await.unwrap();
-        Ok(ControlStream(Box::new(SSHStream { channel })))
+        // Opening the channel counts against the same deadline as the command
+        let started = Instant::now();
+        let channel = time::timeout_at(started + timeout, self.connection.exec_channel(&cmd))
+            .await
+            .map_err(|_| AppError::Timeout {
+                command: cmd.clone(),
+                timeout,
+            })??;
+        Ok(ControlStream(Box::new(SSHStream::new(
+            channel, &cmd, timeout, started,
+        ))))
     }
 
-    async fn exec(&self, cmd: &str) -> Result<(String, u32)> {
-        let cmd = self.build_command(self.sudo, None, cmd);
-        let mut stream = self.exec_open(&cmd).await?;
+    async fn exec_timeout(&self, cmd: &str, timeout: Duration) -> Result<CommandOutput> {
+        let mut stream = self.exec_open_timeout(cmd, timeout).await?;
         stream.wait_for_completion().await
     }
 
     async fn disconnect(&self) -> Result<()> {
-        if self.is_connected().await? {
-            let handle = self.handle.write().await.take();
-            if let Some(mut handle) = handle {
-                handle
-                    .disconnect(Disconnect::ByApplication, "Client closed", "")
-                    .await?;
-            }
-        }
-        Ok(())
+        self.connection.disconnect().await
     }
 
     async fn is_connected(&self) -> Result<bool> {
-        Ok(self.handle.read().await.is_some())
+        Ok(self.connection.is_connected().await)
     }
 }
 
 pub struct SSHStream {
     channel: Channel,
+    command: String,
+    timeout: Duration,
+    started: Instant,
+    deadline: Instant,
+}
+
+impl SSHStream {
+    /// Extended data of this type is the stderr of the command
+    const STDERR: u32 = 1;
+
+    /// The stream times out `timeout` after `started`, when the command was asked for
+    fn new(channel: Channel, command: &str, timeout: Duration, started: Instant) -> Self {
+        SSHStream {
+            channel,
+            command: command.to_string(),
+            timeout,
+            started,
+            deadline: started + timeout,
+        }
+    }
+
+    /// Kill the remote command and close our side of the channel after a timeout
+    async fn abort(&mut self) -> AppError {
+        if let Err(e) = self.channel.signal(Sig::KILL).await {
+            debug!("Failed to signal '{}': {}", self.command, e);
+        }
+        if let Err(e) = self.channel.eof().await {
+            debug!("Failed to close channel of '{}': {}", self.command, e);
+        }
+        AppError::Timeout {
+            command: self.command.clone(),
+            timeout: self.timeout,
+        }
+    }
 }
 
 impl Debug for SSHStream {
@@ -133,48 +409,80 @@ impl Debug for SSHStream {
 
 #[async_trait]
 impl ControlStreamTrait for SSHStream {
-    async fn wait_for_completion(&mut self) -> Result<(String, u32)> {
-        let mut output = Vec::new();
-        let mut code = None;
-        while let Some(msg) = self.channel.wait().await {
-            match msg {
-                thrussh::ChannelMsg::Data { ref data } => {
-                    output.write_all(&data).unwrap();
-                    debug!("{}", std::str::from_utf8(data)?);
-                }
-                thrussh::ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status),
-                thrussh::ChannelMsg::Eof => {
-                    debug!("<EOF>")
+    async fn wait_for_completion(&mut self) -> Result<CommandOutput> {
+        let channel = &mut self.channel;
+        let read = async {
+            let mut stdout = Vec::new();
+            let mut stderr = Vec::new();
+            let mut code = None;
+            while let Some(msg) = channel.wait().await {
+                match msg {
+                    thrussh::ChannelMsg::Data { ref data } => {
+                        stdout.write_all(&data).unwrap();
+                        debug!("{}", std::str::from_utf8(data)?);
+                    }
+                    thrussh::ChannelMsg::ExtendedData { ref data, ext } if ext == Self::STDERR => {
+                        stderr.write_all(&data).unwrap();
+                        debug!("{}", std::str::from_utf8(data)?);
+                    }
+                    thrussh::ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status),
+                    thrussh::ChannelMsg::Eof => {
+                        debug!("<EOF>")
+                    }
+                    _ => {}
                 }
-                _ => {}
             }
-        }
+            Ok::<_, AppError>((stdout, stderr, code))
+        };
+        let result = time::timeout_at(self.deadline, read).await;
+        let (stdout, stderr, code) = match result {
+            Ok(result) => result?,
+            Err(_) => return Err(self.abort().await),
+        };
         self.channel.eof().await?;
-        Ok((std::str::from_utf8(&output)?.into(), code.unwrap_or(256)))
+        Ok(CommandOutput {
+            stdout: String::from_utf8(stdout)?,
+            stderr: String::from_utf8(stderr)?,
+            code: code.unwrap_or(256),
+            duration: self.started.elapsed(),
+        })
     }
 
     async fn wait_for(&mut self, ptrn: &Regex) -> Result<(String, Option<u32>)> {
-        let mut output = Vec::new();
-        let mut code = None;
-        while let Some(msg) = self.channel.wait().await {
-            match msg {
-                thrussh::ChannelMsg::Data { ref data } => {
-                    let str_data = std::str::from_utf8(data).unwrap_or_default();
-                    debug!("{}", str_data);
-
-                    if ptrn.is_match(str_data) {
-                        debug!("Found the pattern we're waiting for...");
-                        break;
+        let channel = &mut self.channel;
+        let read = async {
+            let mut output = Vec::new();
+            let mut code = None;
+            while let Some(msg) = channel.wait().await {
+                match msg {
+                    thrussh::ChannelMsg::Data { ref data } => {
+                        let str_data = std::str::from_utf8(data).unwrap_or_default();
+                        debug!("{}", str_data);
+
+                        if ptrn.is_match(str_data) {
+                            debug!("Found the pattern we're waiting for...");
+                            break;
+                        }
+                        output.write_all(&data).unwrap();
                     }
-                    output.write_all(&data).unwrap();
-                }
-                thrussh::ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status),
-                thrussh::ChannelMsg::Eof => {
-                    debug!("<EOF>")
+                    thrussh::ChannelMsg::ExtendedData { ref data, ext } if ext == Self::STDERR => {
+                        debug!("{}", std::str::from_utf8(data).unwrap_or_default());
+                        output.write_all(&data).unwrap();
+                    }
+                    thrussh::ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status),
+                    thrussh::ChannelMsg::Eof => {
+                        debug!("<EOF>")
+                    }
+                    _ => {}
                 }
-                _ => {}
             }
-        }
+            (output, code)
+        };
+        let result = time::timeout_at(self.deadline, read).await;
+        let (output, code) = match result {
+            Ok(result) => result,
+            Err(_) => return Err(self.abort().await),
+        };
         Ok((std::str::from_utf8(&output)?.into(), code))
     }
 
@@ -0,0 +1,50 @@
+use crate::config::TimeoutOptions;
+use std::future::Future;
+use std::sync::RwLock;
+use tokio::time::{Duration, Instant};
+
+tokio::task_local! {
+    /// Deadline of the gRPC request being served by the current task
+    static DEADLINE: Option<Instant>;
+}
+
+lazy_static! {
+    static ref TIMEOUTS: RwLock<TimeoutOptions> = RwLock::new(Default::default());
+}
+
+/// Replace the timeouts applied to commands that don't specify their own
+pub fn set_timeouts(options: TimeoutOptions) {
+    *TIMEOUTS.write().unwrap() = options;
+}
+
+/// The class of a command is the name of the program it runs up to the first `.`,
+/// so `mkfs.ext4 /dev/sdb` and `mkfs.xfs /dev/sdc` share the `mkfs` class
+pub fn command_class(cmd: &str) -> &str {
+    let program = cmd.split_whitespace().next().unwrap_or_default();
+    let program = program.rsplit('/').next().unwrap_or(program);
+    program.split('.').next().unwrap_or(program)
+}
+
+/// The configured timeout for a command, based on its class
+pub fn timeout_for(cmd: &str) -> Duration {
+    let timeouts = TIMEOUTS.read().unwrap();
+    let secs = timeouts
+        .commands
+        .get(command_class(cmd))
+        .copied()
+        .unwrap_or(timeouts.default);
+    Duration::from_secs(secs)
+}
+
+/// Shorten a timeout so that the command ends before the deadline of the current request
+pub fn clamp(timeout: Duration) -> Duration {
+    match DEADLINE.try_with(|d| *d).ok().flatten() {
+        Some(deadline) => timeout.min(deadline.saturating_duration_since(Instant::now())),
+        None => timeout,
+    }
+}
+
+/// Run a future with a request deadline that applies to every command it executes
+pub async fn with_deadline<F: Future>(deadline: Option<Instant>, f: F) -> F::Output {
+    DEADLINE.scope(deadline, f).await
+}
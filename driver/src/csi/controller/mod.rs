@@ -7,7 +7,11 @@ use super::{
     },
     App,
 };
-use crate::{control::ControlModule, storage::Storage};
+use crate::{
+    control::ControlModule,
+    error::AppError,
+    storage::{Publication, Storage, VolumeEvent, VolumeState, PUBLISHED_NODE_KEY},
+};
 use anyhow::Result;
 use std::cmp::max;
 use tonic::{Request, Response, Status};
@@ -15,6 +19,36 @@ use tonic::{Request, Response, Status};
 const CSI_NAME: &'static str = "csi.storage.k8s.io/pvc/name";
 const CSI_NAMESPACE: &'static str = "csi.storage.k8s.io/pvc/namespace";
 
+/// The entries of a ListVolumes page and the token of the next one. Records are listed in key
+/// order, the token is the index of the next entry.
+fn page<T>(
+    items: Vec<T>,
+    starting_token: &str,
+    max_entries: i32,
+) -> Result<(Vec<T>, String), Status> {
+    let total = items.len();
+    let start = match starting_token {
+        "" => 0,
+        token => token
+            .parse::<usize>()
+            .ok()
+            .filter(|i| *i <= total)
+            .ok_or_else(|| Status::aborted(format!("Invalid starting token '{}'", token)))?,
+    };
+    let count = match max_entries {
+        n if n > 0 => n as usize,
+        _ => total,
+    };
+    let items: Vec<T> = items.into_iter().skip(start).take(count).collect();
+    let next = start + items.len();
+    let next_token = if next < total {
+        next.to_string()
+    } else {
+        String::new()
+    };
+    Ok((items, next_token))
+}
+
 #[tonic::async_trait]
 impl Controller for App {
     async fn controller_get_capabilities(
@@ -38,6 +72,16 @@ impl Controller for App {
                         r#type: rpc::Type::PublishUnpublishVolume.into(),
                     })),
                 },
+                ControllerServiceCapability {
+                    r#type: Some(Type::Rpc(Rpc {
+                        r#type: rpc::Type::ListVolumes.into(),
+                    })),
+                },
+                ControllerServiceCapability {
+                    r#type: Some(Type::Rpc(Rpc {
+                        r#type: rpc::Type::ListVolumesPublishedNodes.into(),
+                    })),
+                },
             ],
         }))
     }
@@ -47,6 +91,7 @@ impl Controller for App {
         request: Request<CreateVolumeRequest>,
     ) -> Result<Response<CreateVolumeResponse>, Status> {
         let message = request.get_ref();
+        let config = self.config();
         info!(
             "[controller] Processing controller create volume request: {:?}",
             message
@@ -68,16 +113,32 @@ impl Controller for App {
             1 * 1024 * 1024 * 1024
         };
 
-        let storage =
-            Storage::new_from_params_secrets(&message.parameters, &message.secrets).await?;
+        let storage = Storage::new_from_params_secrets(
+            &message.parameters,
+            &message.secrets,
+            &config,
+            &self.metadata,
+        )
+        .await?;
         let volume_id = storage.create(&name, provision_size).await?;
+        let _lock = self.volume_locks.lock(&volume_id).await;
+        let volume_context = storage.info().to_params();
+        VolumeState::record(
+            &self.metadata,
+            &volume_id,
+            VolumeEvent::Created {
+                capacity_bytes: provision_size,
+                volume_context: &volume_context,
+            },
+        )
+        .await?;
 
         Ok(Response::new(CreateVolumeResponse {
             volume: Some(Volume {
                 capacity_bytes: provision_size,
                 volume_id,
                 content_source: None,
-                volume_context: message.parameters.clone(),
+                volume_context,
                 accessible_topology: Default::default(),
             }),
         }))
@@ -88,17 +149,35 @@ impl Controller for App {
         request: Request<DeleteVolumeRequest>,
     ) -> Result<Response<DeleteVolumeResponse>, Status> {
         let message = request.get_ref();
+        let config = self.config();
         let volume_id = message.volume_id.as_str();
-        warn!(
-            "[controller] Received request to delete volume id '{}', ignored...",
+        let _lock = self.volume_locks.lock(volume_id).await;
+        info!(
+            "[controller] Processing delete volume request for '{}'",
             volume_id
         );
 
-        let control = ControlModule::from_map(&message.secrets)?;
-        match Storage::new_from_volume_id(volume_id, control, &self.metadata).await {
-            Ok(storage) => storage.delete(volume_id).await?,
-            Err(e) => warn!("Storage delete operation could not be called: {}", e),
+        if self.metadata.get::<Publication>(volume_id).await?.is_some() {
+            return Err(AppError::FailedPrecondition(format!(
+                "Volume '{}' is still published",
+                volume_id
+            ))
+            .into());
         }
+        let control = ControlModule::from_map(&message.secrets, &config, &self.metadata)?;
+        VolumeState::track(&self.metadata, volume_id, VolumeEvent::Deleted, async {
+            // Only a volume whose dataset is gone counts as deleted, anything else keeps the
+            // record so that the dataset isn't left without one
+            if control.zfs().await?.get_dataset(volume_id).await?.is_none() {
+                info!("Volume '{}' does not exist, nothing to delete", volume_id);
+                return Ok(());
+            }
+            Storage::new_from_volume_id(volume_id, control, &self.metadata)
+                .await?
+                .delete(volume_id)
+                .await
+        })
+        .await?;
 
         Ok(Response::new(DeleteVolumeResponse {}))
     }
@@ -108,25 +187,44 @@ impl Controller for App {
         request: Request<ControllerPublishVolumeRequest>,
     ) -> Result<Response<ControllerPublishVolumeResponse>, Status> {
         let message = request.get_ref();
+        let config = self.config();
         info!(
             "[controller] Processing controller publish volume request: {:?}",
             message
         );
         let volume_id = message.volume_id.as_str();
+        let _lock = self.volume_locks.lock(volume_id).await;
         // let readonly = message.readonly; //TODO: Use this
-        // let node_id = message.node_id.as_str(); //TODO: Share to the specified node only
+        let node_id = message.node_id.as_str();
 
         let storage = Storage::new_from_params_secrets_metadata(
             &message.volume_context,
             &message.secrets,
             volume_id,
+            &config,
+            &self.metadata,
+        )
+        .await?;
+        let mut publish_context = VolumeState::track(
             &self.metadata,
+            volume_id,
+            VolumeEvent::Published { node: node_id },
+            storage.publish(volume_id),
         )
         .await?;
-        storage.publish(volume_id).await?;
+        publish_context.extend(storage.info().to_params());
+        publish_context.insert(PUBLISHED_NODE_KEY.to_string(), node_id.to_string());
+        self.metadata
+            .set(
+                volume_id,
+                Publication {
+                    publish_context: publish_context.clone(),
+                },
+            )
+            .await?;
 
         Ok(Response::new(ControllerPublishVolumeResponse {
-            publish_context: Default::default(),
+            publish_context,
         }))
     }
 
@@ -135,16 +233,35 @@ impl Controller for App {
         request: Request<ControllerUnpublishVolumeRequest>,
     ) -> Result<Response<ControllerUnpublishVolumeResponse>, Status> {
         let message = request.get_ref();
+        let config = self.config();
         let volume_id = message.volume_id.as_str();
+        let _lock = self.volume_locks.lock(volume_id).await;
         warn!(
             "[controller] Received request to unpublish volume id '{}'",
             volume_id
         );
 
-        let control = ControlModule::from_map(&message.secrets)?;
-        match Storage::new_from_volume_id(volume_id, control, &self.metadata).await {
-            Ok(storage) => storage.unpublish(volume_id).await?,
-            Err(e) => warn!("Storage delete operation could not be called: {}", e),
+        let control = ControlModule::from_map(&message.secrets, &config, &self.metadata)?;
+        let event = VolumeEvent::Unpublished {
+            node: message.node_id.as_str(),
+        };
+        VolumeState::track(&self.metadata, volume_id, event, async {
+            match Storage::new_from_volume_id(volume_id, control, &self.metadata).await {
+                Ok(storage) => storage.unpublish(volume_id).await?,
+                Err(e) => warn!("Storage unpublish operation could not be called: {}", e),
+            }
+            Ok(())
+        })
+        .await?;
+        // Nodes the volume is still published to keep the publication
+        let published = self
+            .metadata
+            .get::<VolumeState>(volume_id)
+            .await?
+            .map(|state| !state.published.is_empty())
+            .unwrap_or(false);
+        if !published {
+            self.metadata.delete::<Publication>(volume_id).await?;
         }
 
         Ok(Response::new(ControllerUnpublishVolumeResponse {}))
@@ -174,17 +291,32 @@ impl Controller for App {
         request: Request<ListVolumesRequest>,
     ) -> Result<Response<ListVolumesResponse>, Status> {
         let message = request.get_ref();
-        //TODO: List volumes?
-        warn!("[controller] Unhandled ListVolumesRequest: {:?}", message);
-        // ListVolumesRequest {
-        //     max_entries: (),
-        //     starting_token: (),
-        // };
-        // ListVolumesResponse {
-        //     entries: (),
-        //     next_token: (),
-        // };
-        Err(Status::unimplemented("Not implemented!"))
+        info!(
+            "[controller] Processing list volumes request: {:?}",
+            message
+        );
+        let volumes = self.metadata.list::<VolumeState>().await?;
+        let (volumes, next_token) = page(volumes, &message.starting_token, message.max_entries)?;
+        let entries = volumes
+            .into_iter()
+            .map(|(volume_id, state)| list_volumes_response::Entry {
+                volume: Some(Volume {
+                    capacity_bytes: state.capacity_bytes,
+                    volume_id,
+                    content_source: None,
+                    volume_context: state.volume_context,
+                    accessible_topology: Default::default(),
+                }),
+                status: Some(list_volumes_response::VolumeStatus {
+                    published_node_ids: state.published.into_iter().map(|(node, _)| node).collect(),
+                    volume_condition: None,
+                }),
+            })
+            .collect();
+        Ok(Response::new(ListVolumesResponse {
+            entries,
+            next_token,
+        }))
     }
 
     async fn get_capacity(
@@ -271,3 +403,38 @@ impl Controller for App {
         Err(Status::unimplemented("Not implemented!"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_through_all_entries() {
+        let items: Vec<u32> = (0..5).collect();
+        let (first, token) = page(items.clone(), "", 2).unwrap();
+        assert_eq!(first, vec![0, 1]);
+        assert_eq!(token, "2");
+        let (second, token) = page(items.clone(), &token, 2).unwrap();
+        assert_eq!(second, vec![2, 3]);
+        assert_eq!(token, "4");
+        let (last, token) = page(items, &token, 2).unwrap();
+        assert_eq!(last, vec![4]);
+        assert_eq!(token, "");
+    }
+
+    #[test]
+    fn returns_everything_without_max_entries() {
+        let (all, token) = page(vec![1, 2, 3], "", 0).unwrap();
+        assert_eq!(all, vec![1, 2, 3]);
+        assert_eq!(token, "");
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        for token in &["x", "-1", "4"] {
+            let e = page(vec![1, 2, 3], token, 1).unwrap_err();
+            assert_eq!(e.code(), tonic::Code::Aborted);
+        }
+        assert!(page(vec![1, 2, 3], "3", 1).unwrap().0.is_empty());
+    }
+}
@@ -4,6 +4,7 @@ use super::{
 };
 use anyhow::Result;
 use std::collections::HashMap;
+use std::sync::atomic::Ordering;
 use tonic::{Request, Response, Status};
 
 #[tonic::async_trait]
@@ -40,7 +41,9 @@ impl Identity for App {
 
     async fn probe(&self, _: Request<ProbeRequest>) -> Result<Response<ProbeResponse>, Status> {
         info!("[identity] Received probe...");
-        let reply = ProbeResponse { ready: Some(true) };
+        let reply = ProbeResponse {
+            ready: Some(self.ready.load(Ordering::SeqCst)),
+        };
         Ok(Response::new(reply))
     }
 }
@@ -1,11 +1,16 @@
 use self::sock::UnixStream;
+use crate::control::with_deadline;
 pub use crate::App;
 use crate::Result;
+use futures::future::BoxFuture;
 use futures::TryFutureExt;
 use std::path::Path;
+use std::task::{Context, Poll};
 use tokio::fs;
 use tokio::net::UnixListener;
+use tokio::time::{Duration, Instant};
 use tonic::transport::Server;
+use tower::{layer::layer_fn, Service};
 
 mod controller;
 mod identity;
@@ -16,6 +21,47 @@ pub mod spec {
     tonic::include_proto!("csi.v1");
 }
 
+/// Deadline of a request from its `grpc-timeout` header, e.g. `30S` or `500m`
+fn request_deadline(headers: &http::HeaderMap) -> Option<Instant> {
+    let value = headers.get("grpc-timeout")?.to_str().ok()?;
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    let amount: u64 = amount.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(Instant::now() + timeout)
+}
+
+/// Serves every request with its deadline, so that the commands it runs end before the client
+/// gives up on it
+#[derive(Debug, Clone)]
+struct Deadline<S>(S);
+
+impl<S, B> Service<http::Request<B>> for Deadline<S>
+where
+    S: Service<http::Request<B>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, std::result::Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let deadline = request_deadline(request.headers());
+        Box::pin(with_deadline(deadline, self.0.call(request)))
+    }
+}
+
 impl App {
     pub async fn start_csi_services(self) -> Result<()> {
         let controller_service = spec::controller_server::ControllerServer::new(self.clone());
@@ -43,6 +89,7 @@ impl App {
         let rx_drop = self.shutdown_rx.clone();
 
         Server::builder()
+            .layer(layer_fn(Deadline))
             .add_service(controller_service)
             .add_service(identity_service)
             .add_service(node_service)
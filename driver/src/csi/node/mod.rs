@@ -6,7 +6,9 @@ use super::{
     },
     App,
 };
-use crate::storage::Storage;
+use crate::storage::{
+    Staging, StagingState, Storage, VolumeEvent, VolumeState, PUBLISHED_NODE_KEY,
+};
 use anyhow::Result;
 use tonic::{Request, Response, Status};
 
@@ -17,18 +19,58 @@ impl Node for App {
         request: Request<NodeStageVolumeRequest>,
     ) -> Result<Response<NodeStageVolumeResponse>, Status> {
         let message = request.get_ref();
+        let config = self.config();
         info!("[node] Processing stage volume request: {:?}", message);
 
         let vol_id = message.volume_id.as_str();
+        let _lock = self.volume_locks.lock(vol_id).await;
         let staging_path = message.staging_target_path.as_str();
         let storage = Storage::new_from_params(
             &message.volume_context,
-            self.control_node().await?,
+            self.control_node(&config).await?,
             vol_id,
+            &config,
             &self.metadata,
         )
         .await?;
-        storage.stage(vol_id, staging_path).await?;
+        let mount_flags = match message
+            .volume_capability
+            .as_ref()
+            .and_then(|c| c.access_type.as_ref())
+        {
+            Some(volume_capability::AccessType::Mount(m)) => m.mount_flags.clone(),
+            _ => vec![],
+        };
+        let key = Staging::key(&self.node_id, vol_id);
+        let mut staging = Staging {
+            node_id: self.node_id.to_string(),
+            volume_id: vol_id.to_string(),
+            staging_path: staging_path.to_string(),
+            publish_context: message.publish_context.clone(),
+            mount_flags: mount_flags.clone(),
+            target_paths: vec![],
+            state: StagingState::Staging,
+        };
+        if let Some(existing) = self.metadata.get::<Staging>(&key).await? {
+            staging.target_paths = existing.target_paths;
+        }
+        let event = VolumeEvent::Staged {
+            node: &self.node_id,
+            staging_path,
+            published_to: message
+                .publish_context
+                .get(PUBLISHED_NODE_KEY)
+                .map(|n| n.as_str()),
+        };
+        VolumeState::track(&self.metadata, vol_id, event, async {
+            self.metadata.set(&key, staging.clone()).await?;
+            storage
+                .stage(vol_id, staging_path, &message.publish_context, &mount_flags)
+                .await
+        })
+        .await?;
+        staging.state = StagingState::Staged;
+        self.metadata.set(&key, staging).await?;
 
         Ok(Response::new(NodeStageVolumeResponse {}))
     }
@@ -38,18 +80,29 @@ impl Node for App {
         request: Request<NodeUnstageVolumeRequest>,
     ) -> Result<Response<NodeUnstageVolumeResponse>, Status> {
         let message = request.get_ref();
+        let config = self.config();
         info!("[node] Processing unstage volume request: {:?}", message);
-        let storage = Storage::new_from_volume_id(
-            message.volume_id.as_str(),
-            self.control_node().await?,
-            &self.metadata,
-        )
+        let vol_id = message.volume_id.as_str();
+        let _lock = self.volume_locks.lock(vol_id).await;
+        let staging_path = message.staging_target_path.as_str();
+        let control = self.control_node(&config).await?;
+        let event = VolumeEvent::Unstaged {
+            node: &self.node_id,
+        };
+        VolumeState::track(&self.metadata, vol_id, event, async {
+            match Storage::get_node_storage_info(vol_id, &self.metadata).await? {
+                Some(storage_info) => {
+                    Storage::new_from_storage_info(storage_info, control)
+                        .await?
+                        .unstage(vol_id, staging_path)
+                        .await
+                }
+                None => Storage::unstage_path(control, staging_path).await,
+            }
+        })
         .await?;
-        storage
-            .unstage(
-                message.volume_id.as_str(),
-                message.staging_target_path.as_str(),
-            )
+        self.metadata
+            .delete::<Staging>(&Staging::key(&self.node_id, vol_id))
             .await?;
         Ok(Response::new(NodeUnstageVolumeResponse {}))
     }
@@ -59,18 +112,38 @@ impl Node for App {
         request: Request<NodePublishVolumeRequest>,
     ) -> Result<Response<NodePublishVolumeResponse>, Status> {
         let message = request.get_ref();
+        let config = self.config();
         info!("[node] Processing publish volume request: {:?}", message);
         let vol_id = message.volume_id.as_str();
+        let _lock = self.volume_locks.lock(vol_id).await;
         let src = message.staging_target_path.as_str();
         let dst = message.target_path.as_str();
         let storage = Storage::new_from_params(
             &message.volume_context,
-            self.control_node().await?,
+            self.control_node(&config).await?,
             vol_id,
+            &config,
             &self.metadata,
         )
         .await?;
-        storage.mount(vol_id, src, dst).await?;
+        let event = VolumeEvent::Mounted {
+            node: &self.node_id,
+            target_path: dst,
+        };
+        VolumeState::track(
+            &self.metadata,
+            vol_id,
+            event,
+            storage.mount(vol_id, src, dst),
+        )
+        .await?;
+        let key = Staging::key(&self.node_id, vol_id);
+        if let Some(mut staging) = self.metadata.get::<Staging>(&key).await? {
+            if !staging.target_paths.iter().any(|p| p == dst) {
+                staging.target_paths.push(dst.to_string());
+                self.metadata.set(&key, staging).await?;
+            }
+        }
         Ok(Response::new(NodePublishVolumeResponse {}))
     }
 
@@ -79,16 +152,33 @@ impl Node for App {
         request: Request<NodeUnpublishVolumeRequest>,
     ) -> Result<Response<NodeUnpublishVolumeResponse>, Status> {
         let message = request.get_ref();
+        let config = self.config();
         info!("[node] Processing unpublish volume request: {:?}", message);
-        let storage = Storage::new_from_volume_id(
-            message.volume_id.as_str(),
-            self.control_node().await?,
-            &self.metadata,
-        )
+        let vol_id = message.volume_id.as_str();
+        let _lock = self.volume_locks.lock(vol_id).await;
+        let target_path = message.target_path.as_str();
+        let control = self.control_node(&config).await?;
+        let event = VolumeEvent::Unmounted {
+            node: &self.node_id,
+            target_path,
+        };
+        VolumeState::track(&self.metadata, vol_id, event, async {
+            match Storage::get_node_storage_info(vol_id, &self.metadata).await? {
+                Some(storage_info) => {
+                    Storage::new_from_storage_info(storage_info, control)
+                        .await?
+                        .unmount(vol_id, target_path)
+                        .await
+                }
+                None => Storage::unmount_path(control, target_path).await,
+            }
+        })
         .await?;
-        storage
-            .unmount(message.volume_id.as_str(), message.target_path.as_str())
-            .await?;
+        let key = Staging::key(&self.node_id, vol_id);
+        if let Some(mut staging) = self.metadata.get::<Staging>(&key).await? {
+            staging.target_paths.retain(|p| p != target_path);
+            self.metadata.set(&key, staging).await?;
+        }
         Ok(Response::new(NodeUnpublishVolumeResponse {}))
     }
 
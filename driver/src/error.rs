@@ -2,11 +2,29 @@ pub type Result<T> = std::result::Result<T, AppError>;
 
 #[derive(Debug, Display)]
 pub enum AppError {
-    #[display(fmt = "Command failed with code {}{}", code, output)]
+    #[display(fmt = "Command '{}' failed with code {}: {}", command, code, stderr)]
     CommandFailed {
+        command: String,
+        stderr: String,
         code: u32,
-        output: String,
     },
+    #[display(fmt = "targetcli command '{}' failed: {}", command, message)]
+    TargetCLI {
+        command: String,
+        message: String,
+    },
+    #[display(fmt = "Conflict: {}", _0)]
+    Conflict(String),
+    #[display(fmt = "Command '{}' timed out after {:?}", command, timeout)]
+    Timeout {
+        command: String,
+        timeout: std::time::Duration,
+    },
+    #[display(fmt = "Invalid argument: {}", _0)]
+    InvalidArgument(String),
+    /// The volume is not in a state that allows the operation
+    #[display(fmt = "Failed precondition: {}", _0)]
+    FailedPrecondition(String),
     Generic(String),
 }
 
@@ -20,6 +38,12 @@ impl<T: Into<anyhow::Error>> From<T> for AppError {
 impl From<AppError> for tonic::Status {
     fn from(e: AppError) -> Self {
         warn!("{}", e);
-        tonic::Status::aborted(e.to_string())
+        match e {
+            AppError::Conflict(_) => tonic::Status::already_exists(e.to_string()),
+            AppError::Timeout { .. } => tonic::Status::deadline_exceeded(e.to_string()),
+            AppError::InvalidArgument(_) => tonic::Status::invalid_argument(e.to_string()),
+            AppError::FailedPrecondition(_) => tonic::Status::failed_precondition(e.to_string()),
+            _ => tonic::Status::aborted(e.to_string()),
+        }
     }
 }
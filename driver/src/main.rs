@@ -15,8 +15,10 @@ use error::Result;
 use flexi_logger::{AdaptiveFormat, Logger};
 use std::str::FromStr;
 
+mod admin;
 mod app;
 mod args;
+mod check;
 mod config;
 mod control;
 mod csi;
@@ -37,7 +39,29 @@ async fn main() -> Result<()> {
         .set_palette("196;208;31;8;59".into())
         .start()?;
 
-    let app = App::new(args)?;
+    match &args.command {
+        Some(args::Command::CheckConfig { connect }) => {
+            let ok = check::check_config(&args, *connect).await?;
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        Some(args::Command::Metadata { command }) => {
+            if let Err(e) = admin::metadata(&args, command).await {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        Some(args::Command::Gc { .. }) => {
+            if let Err(e) = admin::gc(&args).await {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        None => {}
+    }
+
+    let app = App::new(args).await?;
 
     match app.run().await {
         Ok(_) => {
@@ -0,0 +1,160 @@
+use super::*;
+use sled::Mode;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Attempts to open the database before it is considered unreadable, a lock held by a
+/// previous instance that is still shutting down is usually released within this time
+const OPEN_ATTEMPTS: u32 = 5;
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Records kept in an embedded sled database
+#[derive(Debug)]
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        let db = sled::Config::default()
+            .path(path)
+            .mode(Mode::LowSpace)
+            .cache_capacity(10_000_000) //Small DB, 10M cache is plenty
+            .open()?;
+        Ok(SledStore { db })
+    }
+
+    /// A database that only lives as long as the process
+    pub fn temporary() -> Result<Self> {
+        let db = sled::Config::default().temporary(true).open()?;
+        Ok(SledStore { db })
+    }
+
+    /// Open the database, retrying for a while. An unreadable database stops the driver unless
+    /// `allow_reset` is set, it is then moved to a timestamped backup, its readable records are
+    /// salvaged into a new database and exported next to the backup.
+    pub async fn open(path: PathBuf, allow_reset: bool) -> Result<Self> {
+        let mut attempt = 1;
+        let error = loop {
+            match blocking(path.clone(), Self::new).await {
+                Ok(m) => return Ok(m),
+                Err(e) if attempt >= OPEN_ATTEMPTS => break e,
+                Err(e) => {
+                    warn!(
+                        "Failed to open metadata db {} (attempt {}/{}): {}",
+                        path.display(),
+                        attempt,
+                        OPEN_ATTEMPTS,
+                        e
+                    );
+                    tokio::time::sleep(OPEN_RETRY_INTERVAL).await;
+                    attempt += 1;
+                }
+            }
+        };
+        if !allow_reset || !path.exists() {
+            return Err(AppError::Generic(format!(
+                "Could not open metadata db {}: {}. It holds the only record of the existing volumes, \
+                 start with --allow-metadata-reset to move it aside and salvage what can be read",
+                path.display(),
+                error
+            )));
+        }
+        blocking(path, move |path| Self::reset(path, error)).await
+    }
+
+    fn reset(path: PathBuf, error: AppError) -> Result<Self> {
+        let backup = Self::quarantine(&path)?;
+        error!(
+            "Metadata db {} is unreadable ({}), moved it to {}",
+            path.display(),
+            error,
+            backup.display()
+        );
+        let store = Self::new(path)?;
+        match store.salvage(&backup) {
+            Ok(count) => warn!("Salvaged {} records from {}", count, backup.display()),
+            Err(e) => error!(
+                "No records could be salvaged from {}: {}",
+                backup.display(),
+                e
+            ),
+        }
+        Ok(store)
+    }
+
+    /// Move the database to `<path>.<unix time>.corrupt`
+    fn quarantine(path: &Path) -> Result<PathBuf> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".{}.corrupt", timestamp));
+        let backup = PathBuf::from(backup);
+        std::fs::rename(path, &backup)?;
+        Ok(backup)
+    }
+
+    /// Copy the readable records of a quarantined database into this one and export them to
+    /// `<backup>.salvage.json`, returning how many were recovered
+    fn salvage(&self, backup: &Path) -> Result<usize> {
+        let old = sled::Config::default().path(backup).open()?;
+        let mut export = BTreeMap::new();
+        for item in old.iter() {
+            let (key, val) = match item {
+                Ok(item) => item,
+                Err(e) => {
+                    warn!("Stopped salvaging at an unreadable record: {}", e);
+                    break;
+                }
+            };
+            self.db.insert(&key, val.clone())?;
+            let data =
+                serde_json::from_slice::<Value>(&val).unwrap_or_else(|_| Value::from(val.to_vec()));
+            export.insert(String::from_utf8_lossy(&key).to_string(), data);
+        }
+        self.db.flush()?;
+
+        let mut path = backup.as_os_str().to_owned();
+        path.push(".salvage.json");
+        std::fs::write(&path, serde_json::to_vec_pretty(&export)?)?;
+        Ok(export.len())
+    }
+}
+
+/// Run file IO of the database on a blocking thread
+async fn blocking<F>(path: PathBuf, f: F) -> Result<SledStore>
+where
+    F: FnOnce(PathBuf) -> Result<SledStore> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(path))
+        .await
+        .map_err(|e| AppError::Generic(format!("Opening the metadata db failed: {}", e)))?
+}
+
+#[async_trait]
+impl MetadataTrait for SledStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key.as_bytes())?.map(|v| v.to_vec()))
+    }
+
+    async fn set(&self, key: &str, val: Vec<u8>) -> Result<()> {
+        self.db.insert(key.as_bytes(), val)?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.db.remove(key.as_bytes())?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut result = vec![];
+        for item in self.db.scan_prefix(prefix.as_bytes()) {
+            let (key, val) = item?;
+            result.push((std::str::from_utf8(&key)?.to_string(), val.to_vec()));
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,164 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Records kept in a human readable JSON file, rewritten through a temporary file and a rename
+/// on every change so a crash never leaves it half written. Only one process may use the file,
+/// a lock on `<path>.lock` is held for as long as the store is open.
+#[derive(Debug)]
+pub struct FileStore(Arc<FileInner>);
+
+#[derive(Debug)]
+struct FileInner {
+    path: PathBuf,
+    records: Mutex<BTreeMap<String, Value>>,
+    /// Holds the lock until the store is dropped
+    _lock: File,
+}
+
+impl FileStore {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        let lock = Self::lock(&path)?;
+        let records = if path.exists() {
+            let text = std::fs::read_to_string(&path)?;
+            serde_json::from_str(&text).map_err(|e| {
+                AppError::Generic(format!(
+                    "Could not read metadata file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(FileStore(Arc::new(FileInner {
+            path,
+            records: Mutex::new(records),
+            _lock: lock,
+        })))
+    }
+
+    fn lock(path: &Path) -> Result<File> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(AppError::Generic(format!(
+                "Metadata file {} is in use by another process: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            )));
+        }
+        Ok(file)
+    }
+
+    /// Run a change to the records and write them out on a blocking thread, the file is only
+    /// rewritten when the change returns true
+    async fn update<F>(&self, change: F) -> Result<()>
+    where
+        F: FnOnce(&mut BTreeMap<String, Value>) -> bool + Send + 'static,
+    {
+        let inner = self.0.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut records = inner.records.lock().unwrap();
+            if change(&mut records) {
+                inner.persist(&records)?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|e| AppError::Generic(format!("Metadata file update failed: {}", e)))?
+    }
+}
+
+impl FileInner {
+    fn persist(&self, records: &BTreeMap<String, Value>) -> Result<()> {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(records)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        // The rename only survives a crash once the directory is on disk too
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MetadataTrait for FileStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.0.records.lock().unwrap().get(key) {
+            Some(val) => Ok(Some(serde_json::to_vec(val)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, val: Vec<u8>) -> Result<()> {
+        let val: Value = serde_json::from_slice(&val)?;
+        let key = key.to_string();
+        self.update(move |records| {
+            records.insert(key, val);
+            true
+        })
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let key = key.to_string();
+        self.update(move |records| records.remove(&key).is_some())
+            .await
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let records = self.0.records.lock().unwrap();
+        let mut result = vec![];
+        for (key, val) in records.range(prefix.to_string()..) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            result.push((key.to_string(), serde_json::to_vec(val)?));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn persists_and_locks() {
+        let dir = std::env::temp_dir().join(format!("metal-csi-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metadata.json");
+
+        let store = FileStore::new(path.clone()).unwrap();
+        assert!(FileStore::new(path.clone()).is_err());
+        store.set("a::1", b"{\"x\":1}".to_vec()).await.unwrap();
+        store.set("b::1", b"2".to_vec()).await.unwrap();
+        store.delete("b::1").await.unwrap();
+        drop(store);
+
+        let store = FileStore::new(path).unwrap();
+        assert_eq!(
+            store.get("a::1").await.unwrap(),
+            Some(b"{\"x\":1}".to_vec())
+        );
+        assert_eq!(store.get("b::1").await.unwrap(), None);
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,47 +1,383 @@
+pub use self::embedded::*;
+pub use self::file::*;
+pub use self::zfs::*;
+use crate::config::{ControlMode, MetadataBackend};
+use crate::control::ControlModule;
+use crate::error::AppError;
 use crate::Result;
-use sled::Mode;
-use std::path::PathBuf;
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
 
-pub trait Storeable: Sized {
+mod embedded;
+mod file;
+mod zfs;
+
+pub trait Storeable: Serialize + DeserializeOwned {
     const KEY: &'static str;
-    fn into_bytes(self) -> Result<Vec<u8>>;
-    fn from_bytes(bytes: Vec<u8>) -> Result<Self>;
+    /// Schema version written with new records, bump it when the layout changes and
+    /// convert records of the previous version in `migrate`
+    const VERSION: u32 = 1;
+
+    /// Convert a record of schema version `from` to version `from + 1`
+    fn migrate(from: u32, _data: Value) -> Result<Value> {
+        Err(AppError::Generic(format!(
+            "No migration of {} records from version {}",
+            Self::KEY,
+            from
+        )))
+    }
+
+    /// Decode a record written before the envelope was introduced. Those are bincode in the
+    /// layout the type had then, which has to be kept frozen next to the type.
+    fn decode_legacy(_bytes: &[u8]) -> Result<Self> {
+        Err(AppError::Generic(format!(
+            "{} records have no legacy layout",
+            Self::KEY
+        )))
+    }
 }
 
-#[derive(Debug)]
-pub struct Metadata {
-    db: sled::Db,
+/// Wraps every record so its schema version is known when it is read back
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    data: Value,
 }
 
+/// Raw records by key, keys are `<kind>::<key>` and values are encoded envelopes
+#[async_trait]
+pub trait MetadataTrait: Send + Sync + Debug {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    async fn set(&self, key: &str, val: Vec<u8>) -> Result<()>;
+    async fn delete(&self, key: &str) -> Result<()>;
+    /// Every record whose key starts with the prefix, ordered by key
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata(Arc<Box<dyn MetadataTrait>>);
+
 impl Metadata {
-    pub fn new(path: PathBuf) -> Result<Self> {
-        let db = sled::Config::default()
-            .path(path)
-            .mode(Mode::LowSpace)
-            .cache_capacity(10_000_000) //Small DB, 10M cache is plenty
-            .open()?;
-        Ok(Metadata { db })
+    /// Open the configured backend, `default_path` is used by a sled backend without a path
+    pub async fn new(
+        backend: &MetadataBackend,
+        default_path: &Path,
+        allow_reset: bool,
+    ) -> Result<Self> {
+        match backend {
+            MetadataBackend::Sled { path } => {
+                let path = path.as_deref().unwrap_or(default_path);
+                Ok(Metadata(Arc::new(Box::new(
+                    SledStore::open(path.to_path_buf(), allow_reset).await?,
+                ))))
+            }
+            MetadataBackend::File { path } => Ok(Metadata(Arc::new(Box::new(FileStore::new(
+                path.to_path_buf(),
+            )?)))),
+            MetadataBackend::Zfs {
+                dataset,
+                control_mode,
+            } => {
+                let control_mode = control_mode.as_ref().ok_or_else(|| {
+                    AppError::Generic(
+                        "The zfs metadata backend needs a control mode, set metadata.control_mode \
+                         or controller.control_mode"
+                            .into(),
+                    )
+                })?;
+                if let ControlMode::SSH {
+                    known_hosts_file: None,
+                    host_key: None,
+                    ..
+                } = control_mode
+                {
+                    // Keys trusted on first use would be stored in the metadata being opened
+                    return Err(AppError::Generic(
+                        "The zfs metadata backend needs a host_key or known_hosts_file to verify \
+                         the server"
+                            .into(),
+                    ));
+                }
+                let control = ControlModule::new(control_mode, &Self::temporary()?)?;
+                Ok(Metadata(Arc::new(Box::new(ZFSStore::new(
+                    dataset, control,
+                )))))
+            }
+        }
+    }
+
+    /// A database that only lives as long as the process, for commands that can't open the real one
+    pub fn temporary() -> Result<Self> {
+        Ok(Metadata(Arc::new(Box::new(SledStore::temporary()?))))
+    }
+
+    fn encode<T: Storeable>(val: T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&Envelope {
+            version: T::VERSION,
+            data: serde_json::to_value(val)?,
+        })?)
+    }
+
+    /// Decode a record, running the migrations it needs. Records written before the
+    /// envelope was introduced are bincode and are read with their frozen layout.
+    fn decode<T: Storeable>(key: &str, bytes: &[u8]) -> Result<(T, bool)> {
+        let error = |e: String| {
+            AppError::Generic(format!(
+                "Could not decode {} record '{}': {}",
+                T::KEY,
+                key,
+                e
+            ))
+        };
+        let envelope = match serde_json::from_slice::<Envelope>(bytes) {
+            Ok(envelope) => envelope,
+            Err(_) => {
+                let val = T::decode_legacy(bytes).map_err(|e| error(e.to_string()))?;
+                return Ok((val, true));
+            }
+        };
+        if envelope.version > T::VERSION {
+            return Err(error(format!(
+                "version {} is newer than the supported version {}",
+                envelope.version,
+                T::VERSION
+            )));
+        }
+        let mut data = envelope.data;
+        for version in envelope.version..T::VERSION {
+            data = T::migrate(version, data).map_err(|e| error(e.to_string()))?;
+        }
+        let val = serde_json::from_value(data).map_err(|e| error(e.to_string()))?;
+        Ok((val, envelope.version != T::VERSION))
     }
 
     pub async fn get<T: Storeable>(&self, key: &str) -> Result<Option<T>> {
         let fullkey = format!("{}::{}", T::KEY, key);
-        let raw_val = self.db.get(fullkey.as_bytes())?;
-        if let Some(val) = raw_val {
-            let dat: Vec<u8> = Vec::from(val.as_ref());
-            if let Ok(r) = T::from_bytes(dat) {
-                Ok(Some(r))
-            } else {
-                Ok(None)
-            }
-        } else {
-            Ok(None)
+        match self.0.get(&fullkey).await? {
+            Some(val) => Ok(Some(Self::decode::<T>(key, &val)?.0)),
+            None => Ok(None),
         }
     }
 
     pub async fn set<T: Storeable>(&self, key: &str, val: T) -> Result<()> {
         let fullkey = format!("{}::{}", T::KEY, key);
-        self.db.insert(fullkey.as_bytes(), val.into_bytes()?)?;
-        self.db.flush_async().await?;
-        Ok(())
+        self.0.set(&fullkey, Self::encode(val)?).await
+    }
+
+    pub async fn delete<T: Storeable>(&self, key: &str) -> Result<()> {
+        let fullkey = format!("{}::{}", T::KEY, key);
+        self.0.delete(&fullkey).await
+    }
+
+    /// The keys of all records of the given type, the records are not decoded
+    pub async fn keys<T: Storeable>(&self) -> Result<Vec<String>> {
+        let prefix = format!("{}::", T::KEY);
+        Ok(self
+            .0
+            .scan(&prefix)
+            .await?
+            .into_iter()
+            .map(|(key, _)| key[prefix.len()..].to_string())
+            .collect())
+    }
+
+    /// All records of the given type as they are stored, without decoding them
+    pub async fn scan_raw<T: Storeable>(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let prefix = format!("{}::", T::KEY);
+        Ok(self
+            .0
+            .scan(&prefix)
+            .await?
+            .into_iter()
+            .map(|(key, val)| (key[prefix.len()..].to_string(), val))
+            .collect())
+    }
+
+    /// Decode a record returned by `scan_raw`
+    pub fn decode_raw<T: Storeable>(key: &str, bytes: &[u8]) -> Result<T> {
+        Ok(Self::decode::<T>(key, bytes)?.0)
+    }
+
+    /// Store a record as `scan_raw` returned it. Records written before the envelope was
+    /// introduced are wrapped in one first, backends other than sled only store JSON. Anything
+    /// else is stored exactly as it is.
+    pub async fn set_raw<T: Storeable>(&self, key: &str, val: Vec<u8>) -> Result<()> {
+        let fullkey = format!("{}::{}", T::KEY, key);
+        let val = match serde_json::from_slice::<Envelope>(&val) {
+            Ok(_) => val,
+            Err(_) => match T::decode_legacy(&val) {
+                Ok(legacy) => Self::encode(legacy)?,
+                Err(_) => val,
+            },
+        };
+        self.0.set(&fullkey, val).await
+    }
+
+    /// List all records of the given type along with their keys. Records that can't be
+    /// decoded are logged and left out.
+    pub async fn list<T: Storeable>(&self) -> Result<Vec<(String, T)>> {
+        let prefix = format!("{}::", T::KEY);
+        let mut result = vec![];
+        for (key, val) in self.0.scan(&prefix).await? {
+            let key = key[prefix.len()..].to_string();
+            match Self::decode::<T>(&key, &val) {
+                Ok((r, _)) => result.push((key, r)),
+                Err(e) => warn!("Skipping record: {}", e),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Rewrite every record of the given type at the current schema version, run at startup.
+    /// Records that can't be decoded are logged and left untouched.
+    pub async fn migrate<T: Storeable>(&self) -> Result<usize> {
+        let prefix = format!("{}::", T::KEY);
+        let mut migrated = 0;
+        let mut failures = 0;
+        for (fullkey, val) in self.0.scan(&prefix).await? {
+            let key = &fullkey[prefix.len()..];
+            match Self::decode::<T>(key, &val) {
+                Ok((r, true)) => {
+                    self.0.set(&fullkey, Self::encode(r)?).await?;
+                    migrated += 1;
+                }
+                Ok((_, false)) => {}
+                Err(e) => {
+                    warn!("Not migrating record: {}", e);
+                    failures += 1;
+                }
+            }
+        }
+        if migrated > 0 {
+            info!(
+                "Migrated {} {} records to version {}",
+                migrated,
+                T::KEY,
+                T::VERSION
+            );
+        }
+        if failures > 0 {
+            error!(
+                "{} {} records could not be decoded and were left as they are",
+                failures,
+                T::KEY
+            );
+        }
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        name: String,
+        size: u64,
+    }
+
+    impl Storeable for Record {
+        const KEY: &'static str = "Record";
+        const VERSION: u32 = 2;
+
+        fn migrate(from: u32, mut data: Value) -> Result<Value> {
+            match from {
+                // Version 1 had no size
+                1 => {
+                    data["size"] = Value::from(0);
+                    Ok(data)
+                }
+                _ => Err(AppError::Generic(format!("unknown version {}", from))),
+            }
+        }
+    }
+
+    fn envelope(version: u32, data: Value) -> Vec<u8> {
+        serde_json::to_vec(&Envelope { version, data }).unwrap()
+    }
+
+    #[test]
+    fn decodes_current_version() {
+        let bytes = Metadata::encode(Record {
+            name: "a".into(),
+            size: 1,
+        })
+        .unwrap();
+        let (record, stale) = Metadata::decode::<Record>("a", &bytes).unwrap();
+        assert_eq!(
+            record,
+            Record {
+                name: "a".into(),
+                size: 1
+            }
+        );
+        assert!(!stale);
+    }
+
+    #[test]
+    fn migrates_older_versions() {
+        let bytes = envelope(1, serde_json::json!({ "name": "a" }));
+        let (record, stale) = Metadata::decode::<Record>("a", &bytes).unwrap();
+        assert_eq!(record.size, 0);
+        assert!(stale);
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let bytes = envelope(3, serde_json::json!({ "name": "a", "size": 1 }));
+        assert!(Metadata::decode::<Record>("a", &bytes).is_err());
+    }
+
+    #[test]
+    fn refuses_bincode_without_legacy_layout() {
+        let bytes = bincode::serialize(&("a".to_string(), 1u64)).unwrap();
+        assert!(Metadata::decode::<Record>("a", &bytes).is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_undecodable_records() {
+        let metadata = Metadata::temporary().unwrap();
+        metadata
+            .set(
+                "good",
+                Record {
+                    name: "good".into(),
+                    size: 1,
+                },
+            )
+            .await
+            .unwrap();
+        metadata
+            .0
+            .set(
+                "Record::old",
+                envelope(1, serde_json::json!({ "name": "old" })),
+            )
+            .await
+            .unwrap();
+        metadata
+            .0
+            .set("Record::bad", b"garbage".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(metadata.migrate::<Record>().await.unwrap(), 1);
+        let keys: Vec<String> = metadata
+            .list::<Record>()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["good", "old"]);
+        assert_eq!(
+            metadata.0.get("Record::bad").await.unwrap(),
+            Some(b"garbage".to_vec())
+        );
     }
 }
@@ -0,0 +1,169 @@
+use super::*;
+use crate::control::ControlModule;
+use std::collections::HashMap;
+
+/// Records kept as user properties of a dataset on the storage server. Property names only
+/// allow a few lowercase characters, so each record is stored under a hash of its key and
+/// the value holds both the key and the record.
+#[derive(Debug)]
+pub struct ZFSStore {
+    dataset: String,
+    control: ControlModule,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Property {
+    key: String,
+    value: Value,
+}
+
+impl ZFSStore {
+    const PROPERTY_PREFIX: &'static str = "metal-csi:record.";
+    /// Longest value ZFS accepts for a user property
+    const MAX_VALUE_LEN: usize = 8192;
+
+    pub fn new(dataset: &str, control: ControlModule) -> Self {
+        ZFSStore {
+            dataset: dataset.to_string(),
+            control,
+        }
+    }
+
+    /// FNV-1a, unlike `DefaultHasher` it is stable across releases
+    fn property_name(key: &str) -> String {
+        let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{}{:016x}", Self::PROPERTY_PREFIX, hash)
+    }
+
+    /// Every record on the dataset, keyed by property name
+    async fn properties(&self) -> Result<HashMap<String, Property>> {
+        self.control.connect().await?;
+        let output = self
+            .control
+            .exec_argv_checked(
+                "zfs",
+                &[
+                    "get",
+                    "-H",
+                    "-p",
+                    "-s",
+                    "local",
+                    "-o",
+                    "property,value",
+                    "all",
+                    self.dataset.as_str(),
+                ],
+            )
+            .await?;
+        let mut result = HashMap::new();
+        for line in output.lines() {
+            let mut parts = line.splitn(2, '\t');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.starts_with(Self::PROPERTY_PREFIX) => {
+                    (name, value)
+                }
+                _ => continue,
+            };
+            result.insert(name.to_string(), self.parse(name, value)?);
+        }
+        Ok(result)
+    }
+
+    fn parse(&self, name: &str, value: &str) -> Result<Property> {
+        serde_json::from_str(value).map_err(|e| {
+            AppError::Generic(format!(
+                "Unreadable metadata property {} on {}: {}",
+                name, self.dataset, e
+            ))
+        })
+    }
+
+    /// The record stored under the property of the key, without listing the others
+    async fn property(&self, key: &str) -> Result<Option<Property>> {
+        let name = Self::property_name(key);
+        self.control.connect().await?;
+        let output = self
+            .control
+            .exec_argv_checked(
+                "zfs",
+                &[
+                    "get",
+                    "-H",
+                    "-p",
+                    "-s",
+                    "local",
+                    "-o",
+                    "value",
+                    name.as_str(),
+                    self.dataset.as_str(),
+                ],
+            )
+            .await?;
+        // Nothing is printed for a property that isn't set locally
+        let value = match output.lines().next() {
+            Some(value) if !value.is_empty() && value != "-" => value,
+            _ => return Ok(None),
+        };
+        match self.parse(&name, value)? {
+            p if p.key == key => Ok(Some(p)),
+            p => Err(AppError::Conflict(format!(
+                "Metadata keys '{}' and '{}' share the property {}",
+                key, p.key, name
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl MetadataTrait for ZFSStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.property(key).await? {
+            Some(p) => Ok(Some(serde_json::to_vec(&p.value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, val: Vec<u8>) -> Result<()> {
+        // Refuses to overwrite the record of another key with the same hash
+        self.property(key).await?;
+        let name = Self::property_name(key);
+        let value = serde_json::to_string(&Property {
+            key: key.to_string(),
+            value: serde_json::from_slice(&val)?,
+        })?;
+        if value.len() > Self::MAX_VALUE_LEN {
+            return Err(AppError::Generic(format!(
+                "Metadata record '{}' is too large for a ZFS property",
+                key
+            )));
+        }
+        let property = format!("{}={}", name, value);
+        self.control
+            .exec_argv_checked("zfs", &["set", property.as_str(), self.dataset.as_str()])
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        if self.property(key).await?.is_some() {
+            let name = Self::property_name(key);
+            self.control
+                .exec_argv_checked("zfs", &["inherit", name.as_str(), self.dataset.as_str()])
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut result = vec![];
+        for (_, p) in self.properties().await? {
+            if p.key.starts_with(prefix) {
+                result.push((p.key, serde_json::to_vec(&p.value)?));
+            }
+        }
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(result)
+    }
+}
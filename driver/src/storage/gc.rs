@@ -0,0 +1,564 @@
+use super::iscsi::TargetCLI;
+use super::*;
+use crate::config::GcAction;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Datasets of every parent are moved below `<parent>/<ARCHIVE_DATASET>` when archived
+const ARCHIVE_DATASET: &str = "metal-csi-archive";
+
+/// Volume context and publish context key of the parent dataset of a volume
+const PARENT_DATASET_PARAM: &str = "zfs.parentDataset";
+
+/// When an orphan was first seen, orphans are only removed once the grace period has passed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrphanRecord {
+    pub first_seen: u64,
+}
+
+impl Storeable for OrphanRecord {
+    const KEY: &'static str = "Orphan";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Display)]
+pub enum OrphanKind {
+    // Removed in this order, a target holds its backstore and a backstore its zvol
+    Target,
+    Backstore,
+    Dataset,
+    Publication,
+    StorageInfo,
+    VolumeState,
+}
+
+/// Something on the storage server or in the metadata that no volume uses
+#[derive(Debug, Clone, Serialize)]
+pub struct Orphan {
+    pub kind: OrphanKind,
+    pub name: String,
+    pub reason: String,
+    /// Seconds since the epoch
+    pub first_seen: u64,
+    /// Whether the collector may delete or archive it, orphans that may not belong to the driver
+    /// are only reported
+    pub removable: bool,
+    /// What was done about it, nothing in a dry run or within the grace period
+    pub outcome: Option<String>,
+}
+
+impl Orphan {
+    fn new(kind: OrphanKind, name: &str, reason: &str, first_seen: u64) -> Self {
+        Orphan {
+            kind,
+            name: name.to_string(),
+            reason: reason.to_string(),
+            first_seen,
+            removable: true,
+            outcome: None,
+        }
+    }
+
+    fn report_only(mut self) -> Self {
+        self.removable = false;
+        self
+    }
+
+    fn key(&self) -> String {
+        format!("{}:{}", self.kind, self.name)
+    }
+}
+
+/// Whether a run with `action` removes the orphan at `now`
+fn is_due(action: GcAction, grace_period: u64, orphan: &Orphan, now: u64) -> bool {
+    action != GcAction::Report && orphan.removable && now >= orphan.first_seen + grace_period
+}
+
+/// The datasets that belong to no volume. Only datasets of deleted volumes are removable, a
+/// dataset without properties or records may be a volume created before they were recorded or
+/// something an admin put below the parent.
+fn dataset_orphans(
+    datasets: &HashMap<String, HashMap<String, String>>,
+    records: &HashSet<String>,
+    volumes: &HashSet<String>,
+    now: u64,
+) -> Vec<Orphan> {
+    let mut orphans = vec![];
+    for (name, props) in datasets.iter() {
+        let is_leaf = !datasets
+            .keys()
+            .any(|d| d.starts_with(&format!("{}/", name)));
+        if let Some(deleted) = props.get(StorageInfo::DELETED_PROPERTY) {
+            let deleted = deleted.parse().unwrap_or(now);
+            orphans.push(Orphan::new(
+                OrphanKind::Dataset,
+                name,
+                "volume was deleted and its dataset retained",
+                deleted,
+            ));
+        } else if is_leaf
+            && !props.contains_key(StorageInfo::TYPE_PROPERTY)
+            && !records.contains(name)
+            && !volumes.contains(name)
+        {
+            orphans.push(
+                Orphan::new(
+                    OrphanKind::Dataset,
+                    name,
+                    "dataset has neither volume properties nor a metadata record",
+                    now,
+                )
+                .report_only(),
+            );
+        }
+    }
+    orphans
+}
+
+/// A record whose dataset does not exist. Only records of volumes below a parent dataset that
+/// was scanned on this storage server are removable, the others may belong to a volume on the
+/// storage server of another control profile.
+fn record_orphan(
+    kind: OrphanKind,
+    volume_id: &str,
+    parent: Option<&String>,
+    scanned: &HashSet<String>,
+    now: u64,
+) -> Orphan {
+    match parent.map(|p| p.trim_end_matches('/')) {
+        Some(parent) if scanned.contains(parent) => {
+            Orphan::new(kind, volume_id, "dataset does not exist", now)
+        }
+        _ => Orphan::new(
+            kind,
+            volume_id,
+            "dataset does not exist on this storage server",
+            now,
+        )
+        .report_only(),
+    }
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Record on the dataset of a deleted volume that it is retained, it is an orphan from then on
+pub async fn mark_retained(control: &ControlModule, volume_id: &str) -> Result<()> {
+    let zfs = control.zfs().await?;
+    if zfs.get_dataset(volume_id).await?.is_some() {
+        let mut attrs = HashMap::new();
+        attrs.insert(
+            StorageInfo::DELETED_PROPERTY.to_string(),
+            now()?.to_string(),
+        );
+        zfs.set_attributes(volume_id, &attrs).await?;
+    }
+    Ok(())
+}
+
+/// Compares the datasets below the known parent datasets, the LIO backstores and targets
+/// of the driver and the metadata records, reporting whatever has no counterpart.
+/// Orphans are deleted or archived once they have been seen for the grace period.
+#[derive(Debug)]
+pub struct GarbageCollector {
+    control: ControlModule,
+    metadata: Metadata,
+    action: GcAction,
+    grace_period: u64,
+    parents: Vec<String>,
+    base_iqns: Vec<String>,
+}
+
+impl GarbageCollector {
+    /// The parent datasets and base IQNs of the configured defaults and of every recorded
+    /// volume are scanned, along with `parents`
+    pub async fn new(
+        control: ControlModule,
+        metadata: Metadata,
+        config: &Configuration,
+        action: GcAction,
+        grace_period: u64,
+        parents: &[String],
+    ) -> Result<Self> {
+        let mut parents = parents.to_vec();
+        let mut base_iqns = vec![];
+        parents.extend(config.zfs.parent_dataset.clone());
+        base_iqns.extend(config.iscsi.base_iqn.clone());
+        for (_, info) in metadata.list::<StorageInfo>().await? {
+            match info {
+                StorageInfo::ISCSI { options, zfs } => {
+                    parents.push(zfs.parent_dataset);
+                    base_iqns.push(options.base_iqn);
+                }
+                StorageInfo::NFS { zfs, .. } => parents.push(zfs.parent_dataset),
+            }
+        }
+        let mut parents: Vec<String> = parents
+            .iter()
+            .map(|p| p.trim_end_matches('/').to_string())
+            .filter(|p| !p.is_empty())
+            .collect();
+        parents.sort();
+        parents.dedup();
+        base_iqns.sort();
+        base_iqns.dedup();
+        Ok(GarbageCollector {
+            control,
+            metadata,
+            action,
+            grace_period,
+            parents,
+            base_iqns,
+        })
+    }
+
+    pub async fn run(&self) -> Result<Vec<Orphan>> {
+        let mut orphans = self.find().await?;
+        orphans.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+        // A report leaves the metadata as it is
+        self.track(&mut orphans, self.action != GcAction::Report)
+            .await?;
+
+        let now = now()?;
+        for orphan in orphans.iter_mut() {
+            if !is_due(self.action, self.grace_period, orphan, now) {
+                continue;
+            }
+            let outcome = match self.remove(orphan).await {
+                Ok(outcome) => {
+                    self.metadata.delete::<OrphanRecord>(&orphan.key()).await?;
+                    outcome
+                }
+                Err(e) => format!("failed: {}", e),
+            };
+            info!("{} {}: {}", orphan.kind, orphan.name, outcome);
+            orphan.outcome = Some(outcome);
+        }
+        Ok(orphans)
+    }
+
+    /// Fill in when each orphan was first seen, recording new orphans and forgetting the ones
+    /// that are gone if `persist` is set
+    async fn track(&self, orphans: &mut [Orphan], persist: bool) -> Result<()> {
+        let mut seen: HashMap<String, OrphanRecord> = self
+            .metadata
+            .list::<OrphanRecord>()
+            .await?
+            .into_iter()
+            .collect();
+        for orphan in orphans.iter_mut() {
+            match seen.remove(&orphan.key()) {
+                Some(record) => orphan.first_seen = orphan.first_seen.min(record.first_seen),
+                None if persist => {
+                    let record = OrphanRecord {
+                        first_seen: orphan.first_seen,
+                    };
+                    self.metadata.set(&orphan.key(), record).await?;
+                }
+                None => {}
+            }
+        }
+        if persist {
+            for key in seen.keys() {
+                self.metadata.delete::<OrphanRecord>(key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn find(&self) -> Result<Vec<Orphan>> {
+        let now = now()?;
+        let mut orphans = vec![];
+        let zfs = self.control.zfs().await?;
+
+        let mut datasets: HashMap<String, HashMap<String, String>> = HashMap::new();
+        let mut scanned = HashSet::new();
+        for parent in self.parents.iter() {
+            // Parents of volumes on other storage servers
+            if zfs.get_dataset(parent.as_str()).await?.is_none() {
+                debug!(
+                    "{} does not exist on this storage server, skipping it",
+                    parent
+                );
+                continue;
+            }
+            scanned.insert(parent.clone());
+            let archive = format!("{}/{}", parent, ARCHIVE_DATASET);
+            let properties = &[StorageInfo::TYPE_PROPERTY, StorageInfo::DELETED_PROPERTY];
+            for (name, props) in zfs.list_properties(parent, properties).await? {
+                if &name != parent && name != archive && !name.starts_with(&format!("{}/", archive))
+                {
+                    datasets.insert(name, props);
+                }
+            }
+        }
+        let records: HashSet<String> = self
+            .metadata
+            .keys::<StorageInfo>()
+            .await?
+            .into_iter()
+            .collect();
+        let volumes: HashSet<String> = self
+            .metadata
+            .keys::<VolumeState>()
+            .await?
+            .into_iter()
+            .collect();
+
+        orphans.extend(dataset_orphans(&datasets, &records, &volumes, now));
+        // The parent dataset of every recorded volume, the storage info is the most reliable
+        let mut record_parents = HashMap::new();
+        for (volume_id, state) in self.metadata.list::<VolumeState>().await? {
+            if let Some(parent) = state.volume_context.get(PARENT_DATASET_PARAM) {
+                record_parents.insert(volume_id, parent.clone());
+            }
+        }
+        for (volume_id, publication) in self.metadata.list::<Publication>().await? {
+            if let Some(parent) = publication.publish_context.get(PARENT_DATASET_PARAM) {
+                record_parents.insert(volume_id, parent.clone());
+            }
+        }
+        for (volume_id, info) in self.metadata.list::<StorageInfo>().await? {
+            let parent = match info {
+                StorageInfo::ISCSI { zfs, .. } | StorageInfo::NFS { zfs, .. } => zfs.parent_dataset,
+            };
+            record_parents.insert(volume_id, parent);
+        }
+        let publications: HashSet<String> = self
+            .metadata
+            .keys::<Publication>()
+            .await?
+            .into_iter()
+            .collect();
+        for (kind, keys) in [
+            (OrphanKind::StorageInfo, &records),
+            (OrphanKind::VolumeState, &volumes),
+            (OrphanKind::Publication, &publications),
+        ]
+        .iter()
+        {
+            for volume_id in keys.iter() {
+                if !datasets.contains_key(volume_id)
+                    && zfs.get_dataset(volume_id.as_str()).await?.is_none()
+                {
+                    let parent = record_parents.get(volume_id);
+                    orphans.push(record_orphan(*kind, volume_id, parent, &scanned, now));
+                }
+            }
+        }
+
+        // Storage servers that only serve NFS have no LIO to scan
+        if self.base_iqns.is_empty() {
+            return Ok(orphans);
+        }
+        if !self.control.missing_tools(&["targetcli"]).await?.is_empty() {
+            warn!("targetcli was not found, skipping LIO objects");
+            return Ok(orphans);
+        }
+        orphans.extend(self.lio_orphans(&zfs, &datasets, now).await?);
+        Ok(orphans)
+    }
+
+    /// Only LIO objects named the way the driver names them are considered. Whether they are in
+    /// use is decided from LIO and ZFS alone, records may be missing: a backstore is live while
+    /// an initiator has it mapped or its zvol exists without being marked deleted, a target
+    /// while it has ACLs or maps a live backstore.
+    async fn lio_orphans(
+        &self,
+        zfs: &ZFS,
+        datasets: &HashMap<String, HashMap<String, String>>,
+        now: u64,
+    ) -> Result<Vec<Orphan>> {
+        let mut orphans = vec![];
+        let mut targetcli = self.control.get_targetcli().await?;
+        let backstores = targetcli.list_backstores().await?;
+        let mapped = targetcli.session_backstores().await?;
+        let mut targets = vec![];
+        for iqn in targetcli.list_iscsi_devices().await? {
+            if self
+                .base_iqns
+                .iter()
+                .any(|b| iqn.starts_with(&format!("{}:", b)))
+            {
+                let luns = targetcli.list_luns(&iqn).await?;
+                let acls = targetcli.list_acls(&iqn).await?;
+                targets.push((iqn, luns, acls));
+            }
+        }
+        targetcli.close().await?;
+
+        let mut dead = HashMap::new();
+        for (name, dev) in backstores.iter() {
+            if !name.starts_with(&TargetCLI::backstore_name("")) || mapped.contains(name) {
+                continue;
+            }
+            let volume_id = match dev.strip_prefix(&TargetCLI::zvol_path("")) {
+                Some(volume_id) => volume_id,
+                None => continue,
+            };
+            let reason = match datasets.get(volume_id) {
+                Some(props) if props.contains_key(StorageInfo::DELETED_PROPERTY) => {
+                    "volume was deleted"
+                }
+                Some(_) => continue,
+                None if zfs.get_dataset(volume_id).await?.is_some() => continue,
+                None => "volume does not exist",
+            };
+            dead.insert(name.as_str(), reason);
+        }
+
+        for (name, reason) in dead.iter() {
+            orphans.push(Orphan::new(OrphanKind::Backstore, name, reason, now));
+        }
+        for (iqn, luns, acls) in targets.iter() {
+            if !acls.is_empty() {
+                continue;
+            }
+            let reason = match luns.first() {
+                None => Some("target maps no volume"),
+                Some(_) if luns.iter().all(|(_, b)| dead.contains_key(b.as_str())) => {
+                    dead.get(luns[0].1.as_str()).copied()
+                }
+                Some(_) => None,
+            };
+            if let Some(reason) = reason {
+                orphans.push(Orphan::new(OrphanKind::Target, iqn, reason, now));
+            }
+        }
+        Ok(orphans)
+    }
+
+    async fn remove(&self, orphan: &Orphan) -> Result<String> {
+        match orphan.kind {
+            OrphanKind::Target | OrphanKind::Backstore => {
+                let mut targetcli = self.control.get_targetcli().await?;
+                match orphan.kind {
+                    OrphanKind::Target => targetcli.delete_target(&orphan.name).await?,
+                    _ => targetcli.delete_backstore(&orphan.name).await?,
+                }
+                targetcli.save_config().await?;
+                targetcli.close().await?;
+                Ok("deleted".into())
+            }
+            OrphanKind::Dataset if self.action == GcAction::Archive => {
+                let parent = self
+                    .parents
+                    .iter()
+                    .filter(|p| orphan.name.starts_with(&format!("{}/", p)))
+                    .max_by_key(|p| p.len())
+                    .ok_or_else(|| {
+                        AppError::Generic(format!("{} has no known parent dataset", orphan.name))
+                    })?;
+                let archive = format!("{}/{}", parent, ARCHIVE_DATASET);
+                let zfs = self.control.zfs().await?;
+                if zfs.get_dataset(archive.as_str()).await?.is_none() {
+                    zfs.create_dataset(archive.as_str(), None).await?;
+                }
+                let name = orphan.name[parent.len() + 1..].replace("/", "-");
+                let target = format!("{}/{}", archive, name);
+                zfs.rename_dataset(&orphan.name, &target).await?;
+                Ok(format!("archived to {}", target))
+            }
+            OrphanKind::Dataset => {
+                self.control
+                    .zfs()
+                    .await?
+                    .destroy_dataset(&orphan.name)
+                    .await?;
+                Ok("destroyed".into())
+            }
+            OrphanKind::Publication => {
+                self.metadata.delete::<Publication>(&orphan.name).await?;
+                Ok("deleted".into())
+            }
+            OrphanKind::StorageInfo => {
+                self.metadata.delete::<StorageInfo>(&orphan.name).await?;
+                Ok("deleted".into())
+            }
+            OrphanKind::VolumeState => {
+                self.metadata.delete::<VolumeState>(&orphan.name).await?;
+                Ok("deleted".into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datasets(entries: &[(&str, &[(&str, &str)])]) -> HashMap<String, HashMap<String, String>> {
+        entries
+            .iter()
+            .map(|(name, props)| {
+                let props = props
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect();
+                (name.to_string(), props)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_of_other_storage_servers_survive_a_delete_run() {
+        let scanned: HashSet<String> = vec!["tank/k8s".to_string()].into_iter().collect();
+        // Deleted behind the driver's back on the scanned server
+        let gone = record_orphan(
+            OrphanKind::StorageInfo,
+            "tank/k8s/pvc-1",
+            Some(&"tank/k8s/".to_string()),
+            &scanned,
+            0,
+        );
+        assert!(is_due(GcAction::Delete, 60, &gone, 1000));
+        // A volume of a second control profile
+        for kind in &[
+            OrphanKind::StorageInfo,
+            OrphanKind::VolumeState,
+            OrphanKind::Publication,
+        ] {
+            let other = record_orphan(
+                *kind,
+                "ssd/k8s/pvc-2",
+                Some(&"ssd/k8s/".to_string()),
+                &scanned,
+                0,
+            );
+            assert!(!other.removable);
+            assert!(!is_due(GcAction::Delete, 60, &other, 1000));
+        }
+        let unknown = record_orphan(OrphanKind::VolumeState, "pvc-3", None, &scanned, 0);
+        assert!(!is_due(GcAction::Delete, 60, &unknown, 1000));
+    }
+
+    #[test]
+    fn unrecorded_datasets_survive_a_delete_run() {
+        let datasets = datasets(&[
+            // Created before volumes were recorded on their datasets and never published
+            ("tank/k8s/pvc-old", &[]),
+            (
+                "tank/k8s/pvc-gone",
+                &[(StorageInfo::DELETED_PROPERTY, "100")],
+            ),
+            (
+                "tank/k8s/pvc-live",
+                &[(StorageInfo::TYPE_PROPERTY, "iscsi")],
+            ),
+        ]);
+        let mut orphans = dataset_orphans(&datasets, &HashSet::new(), &HashSet::new(), 1000);
+        orphans.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(orphans.len(), 2);
+
+        let gone = &orphans[0];
+        assert_eq!(gone.name, "tank/k8s/pvc-gone");
+        assert!(is_due(GcAction::Delete, 60, gone, 1000));
+        // Seen long before the grace period ended
+        let mut old = orphans[1].clone();
+        old.first_seen = 0;
+        assert_eq!(old.name, "tank/k8s/pvc-old");
+        assert!(!old.removable);
+        assert!(!is_due(GcAction::Delete, 60, &old, 1000));
+        assert!(!is_due(GcAction::Archive, 60, &old, 1000));
+    }
+}
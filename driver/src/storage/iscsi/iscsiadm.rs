@@ -1,12 +1,20 @@
 use super::*;
 use regex::Regex;
+use std::net::IpAddr;
+use tokio::time::{Duration, Instant};
 
 #[derive(Debug, Deref, DerefMut, From, Into)]
 pub struct Iscsiadm(ControlModule);
 
 lazy_static! {
-    static ref SESSION_LIST: Regex =
-        Regex::new(r#" (?P<ip>\d+\.\d+\.\d+\.\d+):(?P<port>\d+),\d+ (?P<iqn>\S+) "#).unwrap();
+    static ref SESSION_LIST: Regex = Regex::new(
+        r#"\[(?P<sid>\d+)\] (?:\[(?P<ip6>[^\]\s]+)\]|(?P<ip4>[^\[\s:]+)):(?P<port>\d+),\d+ (?P<iqn>\S+)"#
+    )
+    .unwrap();
+    static ref SESSION_DISK: Regex = Regex::new(
+        r#"scsi(?P<host>\d+) Channel \d+ Id \d+ Lun: (?P<lun>\d+)\s+Attached scsi disk (?P<disk>\w+)"#
+    )
+    .unwrap();
 }
 
 impl Iscsiadm {
@@ -15,85 +23,295 @@ impl Iscsiadm {
         format!("{}:{}", base_iqn, normalized_id)
     }
 
-    pub async fn login(&self, target_name: &str, portal: &str) -> Result<()> {
-        for s in self.sessions().await? {
-            info!("{:?}", s);
-            if s.iqn == target_name {
-                return Ok(());
-            }
+    /// Log into the target unless a session already exists, returning the active session
+    pub async fn login(&self, target_name: &str, portal: &Portal) -> Result<Session> {
+        let addrs = portal.resolve().await?;
+        if let Some(s) = self.find_session(target_name, portal, &addrs).await? {
+            info!("Found existing session {:?}", s);
+            return Ok(s);
         }
-        self.exec_checked(&format!(
-            "iscsiadm --mode node --targetname '{0}' --portal '{1}' --login",
-            target_name, portal
-        ))
+        let node_portal = Portal::address(&addrs[0], portal.port);
+        self.exec_argv_checked(
+            "iscsiadm",
+            &[
+                "--mode",
+                "node",
+                "--targetname",
+                target_name,
+                "--portal",
+                node_portal.as_str(),
+                "--login",
+            ],
+        )
         .await?;
+        self.find_session(target_name, portal, &addrs)
+            .await?
+            .ok_or_else(|| {
+                AppError::Generic(format!(
+                    "Logged into {} but no session was found for portal {}",
+                    target_name, portal
+                ))
+            })
+    }
+
+    pub async fn logout(&self, target_name: &str, portal: &Portal) -> Result<()> {
+        let addrs = portal.resolve().await?;
+        if let Some(s) = self.find_session(target_name, portal, &addrs).await? {
+            self.logout_session(&s).await?;
+        }
         Ok(())
     }
 
-    pub async fn logout(&self, target_name: &str, portal: &str) -> Result<()> {
-        self.exec(&format!(
-            "iscsiadm --mode node --targetname '{0}' --portal '{1}' --logout",
-            target_name, portal
-        ))
+    pub async fn logout_session(&self, session: &Session) -> Result<()> {
+        self.exec_argv(
+            "iscsiadm",
+            &[
+                "--mode",
+                "node",
+                "--targetname",
+                session.iqn.as_str(),
+                "--portal",
+                session.portal().as_str(),
+                "--logout",
+            ],
+        )
         .await?;
         Ok(())
     }
 
-    pub async fn discovery(&self, portal: &str) -> Result<()> {
-        self.exec_checked(&format!(
-            "iscsiadm -m discovery -t sendtargets -p '{0}'",
-            portal
-        ))
+    /// Log out of the session a disk, e.g. `sdb`, is attached through, if any
+    pub async fn logout_disk(&self, disk: &str) -> Result<()> {
+        for session in self.sessions().await? {
+            let disks = self.session_disks(&session).await?;
+            if disks.iter().any(|d| d.name == disk) {
+                info!("Logging out of session {:?} of disk {}", session, disk);
+                return self.logout_session(&session).await;
+            }
+        }
+        debug!("Disk {} is not attached through an iSCSI session", disk);
+        Ok(())
+    }
+
+    pub async fn discovery(&self, portal: &Portal) -> Result<()> {
+        self.exec_argv_checked(
+            "iscsiadm",
+            &[
+                "-m",
+                "discovery",
+                "-t",
+                "sendtargets",
+                "-p",
+                &portal.to_string(),
+            ],
+        )
         .await?;
         Ok(())
     }
 
     pub async fn sessions(&self) -> Result<Vec<Session>> {
-        let (output, code) = self.exec("iscsiadm -m session").await?;
-        let mut result = vec![];
-        if code == 0 {
-            for cap in SESSION_LIST.captures_iter(output.as_str()) {
-                result.push(Session {
-                    ip: cap["ip"].to_string(),
-                    port: cap["port"].to_string(),
-                    iqn: cap["iqn"].to_string(),
-                });
-            }
-        }
-        info!("{} - {:?}", output, result);
+        let output = self.exec_argv("iscsiadm", &["-m", "session"]).await?;
+        // No session is an error exit
+        let result = match output.success() {
+            true => Session::parse(&output.stdout),
+            false => vec![],
+        };
+        info!("{} - {:?}", output.stdout.trim(), result);
         Ok(result)
     }
 
-    pub async fn wait_for_disk(&self, target_name: &str, portal: &str) -> Result<String> {
-        debug!("Waiting on disk...");
-        let disk_path = format!(
-            "/dev/disk/by-path/ip-{}:3260-iscsi-{}-lun-0",
-            portal, target_name
-        );
-        let cmd = format!("test -b '{}'", disk_path);
-        let mut tries = 0;
-        let mut code = 1;
-        while code == 1 && tries < 30 {
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-            let (_, c) = self.exec(&cmd).await?;
-            code = c;
-            tries = tries + 1;
+    pub async fn find_session(
+        &self,
+        target_name: &str,
+        portal: &Portal,
+        addrs: &[IpAddr],
+    ) -> Result<Option<Session>> {
+        Ok(self
+            .sessions()
+            .await?
+            .into_iter()
+            .find(|s| s.iqn == target_name && portal.matches(addrs, &s.ip, &s.port)))
+    }
+
+    /// List the disks attached to the SCSI host of a session
+    pub async fn session_disks(&self, session: &Session) -> Result<Vec<SessionDisk>> {
+        let output = self
+            .exec_argv_checked(
+                "iscsiadm",
+                &["-m", "session", "-r", &session.sid, "-P", "3"],
+            )
+            .await?;
+        let result = SessionDisk::parse(&output)?;
+        debug!("Session {} has disks {:?}", session.sid, result);
+        Ok(result)
+    }
+
+    pub async fn rescan(&self, session: &Session) -> Result<()> {
+        self.exec_argv_checked(
+            "iscsiadm",
+            &["-m", "session", "-r", &session.sid, "--rescan"],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Wait for udev to finish processing events, up to the given timeout
+    pub async fn settle(&self, timeout: Duration) -> Result<()> {
+        let secs = std::cmp::max(1, timeout.as_secs());
+        let output = self
+            .exec_argv("udevadm", &["settle", &format!("--timeout={}", secs)])
+            .await?;
+        if !output.success() {
+            debug!(
+                "udevadm settle exited with code {}: {}",
+                output.code,
+                output.stderr.trim()
+            );
         }
+        Ok(())
+    }
 
-        if code != 0 {
-            Err(AppError::Generic(format!(
-                "Timed out waiting for device {}",
-                disk_path
-            )))
-        } else {
-            Ok(disk_path)
+    /// Resolve the block device for a LUN through the SCSI host of the session
+    pub async fn wait_for_disk(
+        &self,
+        session: &Session,
+        lun: Option<u32>,
+        timeout: Duration,
+    ) -> Result<String> {
+        debug!(
+            "Waiting on disk for session {:?}, lun {:?}...",
+            session, lun
+        );
+        let deadline = Instant::now() + timeout;
+        let mut rescanned = false;
+        loop {
+            self.settle(deadline.saturating_duration_since(Instant::now()))
+                .await?;
+            for disk in self.session_disks(session).await? {
+                if lun.map(|l| l == disk.lun).unwrap_or(true) {
+                    let disk_path = format!("/dev/{}", disk.name);
+                    let output = self.exec_argv("test", &["-b", &disk_path]).await?;
+                    if output.success() {
+                        info!("Found {} on scsi host {}", disk_path, disk.host);
+                        return Ok(disk_path);
+                    }
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(AppError::Generic(format!(
+                    "Timed out waiting for lun {:?} of target {}",
+                    lun, session.iqn
+                )));
+            }
+            if !rescanned {
+                self.rescan(session).await?;
+                rescanned = true;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
         }
     }
 }
 
 #[derive(Debug)]
 pub struct Session {
+    pub sid: String,
     pub ip: String,
     pub port: String,
     pub iqn: String,
 }
+
+impl Session {
+    /// The sessions in the output of `iscsiadm -m session`
+    fn parse(output: &str) -> Vec<Session> {
+        SESSION_LIST
+            .captures_iter(output)
+            .map(|cap| {
+                let ip = cap.name("ip6").or_else(|| cap.name("ip4")).unwrap();
+                Session {
+                    sid: cap["sid"].to_string(),
+                    ip: ip.as_str().to_string(),
+                    port: cap["port"].to_string(),
+                    iqn: cap["iqn"].to_string(),
+                }
+            })
+            .collect()
+    }
+
+    /// The portal address of the session in `ip:port` or `[ip]:port` form
+    pub fn portal(&self) -> String {
+        if self.ip.contains(':') {
+            format!("[{}]:{}", self.ip, self.port)
+        } else {
+            format!("{}:{}", self.ip, self.port)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionDisk {
+    pub host: u32,
+    pub lun: u32,
+    pub name: String,
+}
+
+impl SessionDisk {
+    /// The disks in the output of `iscsiadm -m session -r <sid> -P 3`
+    fn parse(output: &str) -> Result<Vec<SessionDisk>> {
+        let mut result = vec![];
+        for cap in SESSION_DISK.captures_iter(output) {
+            result.push(SessionDisk {
+                host: cap["host"].parse()?,
+                lun: cap["lun"].parse()?,
+                name: cap["disk"].to_string(),
+            });
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ipv4_and_ipv6_sessions() {
+        let sessions = Session::parse(
+            "tcp: [1] 10.0.0.5:3260,1 iqn.2003-01.org.example:tank-k8s-pvc-1 (non-flash)\n\
+             tcp: [12] [fd00::5]:3261,1 iqn.2003-01.org.example:tank-k8s-pvc-2 (non-flash)\n\
+             tcp: [3] [fe80::1%eth0]:3260,1 iqn.2003-01.org.example:pvc-3 (non-flash)\n",
+        );
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions[0].sid, "1");
+        assert_eq!(sessions[0].portal(), "10.0.0.5:3260");
+        assert_eq!(sessions[0].iqn, "iqn.2003-01.org.example:tank-k8s-pvc-1");
+        assert_eq!(sessions[1].sid, "12");
+        assert_eq!(sessions[1].ip, "fd00::5");
+        assert_eq!(sessions[1].portal(), "[fd00::5]:3261");
+        assert_eq!(sessions[2].ip, "fe80::1%eth0");
+        assert!(Session::parse("iscsiadm: No active sessions.\n").is_empty());
+    }
+
+    #[test]
+    fn parses_the_disks_of_a_session() {
+        let disks = SessionDisk::parse(
+            "\t\t************************\n\
+             \t\tAttached SCSI devices:\n\
+             \t\t************************\n\
+             \t\tHost Number: 7\tState: running\n\
+             \t\tscsi7 Channel 00 Id 0 Lun: 0\n\
+             \t\t\tAttached scsi disk sdb\t\tState: running\n\
+             \t\tscsi7 Channel 00 Id 0 Lun: 1\n\
+             \t\t\tAttached scsi disk sdc\t\tState: running\n",
+        )
+        .unwrap();
+        assert_eq!(disks.len(), 2);
+        assert_eq!(
+            (disks[0].host, disks[0].lun, disks[0].name.as_str()),
+            (7, 0, "sdb")
+        );
+        assert_eq!(
+            (disks[1].host, disks[1].lun, disks[1].name.as_str()),
+            (7, 1, "sdc")
+        );
+    }
+}
@@ -1,11 +1,14 @@
 pub use self::iscsiadm::*;
 pub use self::options::*;
+pub use self::portal::*;
 pub use self::targetcli::*;
 use super::*;
 use crate::{control::ControlModule, control::ControlStream};
+use tokio::time::Duration;
 
 mod iscsiadm;
 mod options;
+mod portal;
 mod targetcli;
 
 #[derive(Debug)]
@@ -15,48 +18,151 @@ pub struct ISCSIModule {
     pub control: ControlModule,
 }
 
+impl ISCSIModule {
+    const LUN_CONTEXT_KEY: &'static str = "lun";
+
+    /// Remove the target and the backstore of a volume, the zvol is busy while they exist
+    async fn remove_target(&self, volume_id: &str) -> Result<()> {
+        let mut targetcli = self.control.get_targetcli().await?;
+        let iqn = self.options.target_iqn(volume_id);
+        let mut changed = false;
+        if targetcli.list_iscsi_devices().await?.contains(&iqn) {
+            targetcli.delete_target(&iqn).await?;
+            changed = true;
+        }
+        let backstore = TargetCLI::backstore_name(volume_id);
+        if targetcli.list_backstores().await?.contains_key(&backstore) {
+            targetcli.delete_backstore(&backstore).await?;
+            changed = true;
+        }
+        if changed {
+            targetcli.save_config().await?;
+        }
+        targetcli.close().await
+    }
+}
+
 #[async_trait]
 impl StorageModule for ISCSIModule {
+    fn info(&self) -> StorageInfo {
+        StorageInfo::ISCSI {
+            options: self.options.clone(),
+            zfs: self.zfs.clone(),
+        }
+    }
+
     async fn create(&self, name: &str, provision_size: i64) -> Result<String> {
         info!("Creating {}", name);
         let parent_dataset = self.zfs.parent_dataset.as_str();
         let dataset_name = format!("{}{}", parent_dataset, name);
+        ZFS::validate_name(&dataset_name)?;
         let zfs = self.control.zfs().await?;
         let dataset = zfs.get_dataset(dataset_name.as_str()).await?;
         if dataset.is_none() {
             zfs.create_dataset(dataset_name.as_str(), Some(provision_size))
                 .await?;
         }
-        zfs.set_attributes(&dataset_name, &self.zfs.attributes)
-            .await?;
+        let mut attrs = self.zfs.attributes.clone();
+        attrs.extend(self.info().properties());
+        zfs.set_attributes(&dataset_name, &attrs).await?;
         Ok(dataset_name)
     }
 
     async fn delete(&self, volume_id: &str) -> Result<()> {
         info!("Delete {}", volume_id);
-        warn!("[iscsi] Ignoring deletion of volume '{}'", volume_id);
+        ZFS::validate_name(volume_id)?;
+        self.remove_target(volume_id).await?;
+        match self.zfs.reclaim_policy {
+            ReclaimPolicy::Retain => {
+                warn!(
+                    "[iscsi] Retaining dataset of deleted volume '{}'",
+                    volume_id
+                );
+                mark_retained(&self.control, volume_id).await?
+            }
+            ReclaimPolicy::Delete => self.control.zfs().await?.destroy_dataset(volume_id).await?,
+        }
         Ok(())
     }
 
-    async fn publish(&self, volume_id: &str) -> Result<()> {
+    async fn publish(&self, volume_id: &str) -> Result<HashMap<String, String>> {
         info!("Publish {}", volume_id);
+        ZFS::validate_name(volume_id)?;
         let mut targetcli = self.control.get_targetcli().await?;
-        let backstore = targetcli.create_backstore(volume_id).await?;
 
-        let base_iqn = self.options.base_iqn.as_str();
+        let zvol = TargetCLI::zvol_path(volume_id);
+        let backstore = TargetCLI::backstore_name(volume_id);
+        match targetcli.list_backstores().await?.get(&backstore) {
+            Some(dev) if dev != &zvol => {
+                return Err(AppError::Conflict(format!(
+                    "Backstore {} points at {} instead of {}",
+                    backstore, dev, zvol
+                )));
+            }
+            Some(_) => debug!("Backstore {} already exists", backstore),
+            None => {
+                targetcli.create_backstore(volume_id).await?;
+            }
+        }
 
-        let iqn = targetcli.create_target(base_iqn, volume_id).await?;
+        let zfs = self.control.zfs().await?;
+        let sparse = zfs
+            .get_dataset(volume_id)
+            .await?
+            .map(|d| d.is_sparse())
+            .unwrap_or_default();
+        zfs.set_attributes(volume_id, &self.info().properties())
+            .await?;
+        let current = targetcli.get_backstore_attributes(&backstore).await?;
+        for (key, val) in self.options.backstore_attributes(sparse).iter() {
+            if current.get(key).map(|v| v.to_string()).as_deref() == Some(val.trim()) {
+                continue;
+            }
+            targetcli
+                .set_backstore_attribute(&backstore, key.as_str(), val.as_str())
+                .await?;
+        }
 
-        targetcli.set_target_backstore(&iqn, &backstore).await?;
+        let iqn = self.options.target_iqn(volume_id);
+        if targetcli.list_iscsi_devices().await?.contains(&iqn) {
+            debug!("Target {} already exists", iqn);
+        } else {
+            targetcli
+                .create_target(&self.options.base_iqn, volume_id)
+                .await?;
+        }
+        targetcli
+            .ensure_portal(&iqn, &self.options.portal()?)
+            .await?;
 
+        let luns = targetcli.list_luns(&iqn).await?;
+        let lun = match luns.iter().find(|(_, b)| b == &backstore) {
+            Some((lun, _)) => *lun,
+            None if !luns.is_empty() => {
+                return Err(AppError::Conflict(format!(
+                    "Target {} already maps other backstores: {:?}",
+                    iqn, luns
+                )));
+            }
+            None => targetcli.set_target_backstore(&iqn, &backstore).await?,
+        };
+
+        let current = targetcli.get_target_attributes(&iqn, "tpg1").await?;
         for (key, val) in self.options.attributes.iter() {
+            if current.get(key).map(|v| v.to_string()).as_deref() == Some(val.trim()) {
+                continue;
+            }
             targetcli
                 .set_attribute(&iqn, key.as_str(), val.as_str())
                 .await?;
         }
 
+        targetcli.save_config().await?;
         targetcli.close().await?;
-        Ok(())
+
+        let mut publish_context = HashMap::new();
+        publish_context.insert(Self::LUN_CONTEXT_KEY.to_string(), lun.to_string());
+        Ok(publish_context)
     }
 
     async fn unpublish(&self, volume_id: &str) -> Result<()> {
@@ -65,16 +171,28 @@ impl StorageModule for ISCSIModule {
         Ok(())
     }
 
-    async fn stage(&self, volume_id: &str, staging_path: &str) -> Result<()> {
+    async fn stage(
+        &self,
+        volume_id: &str,
+        staging_path: &str,
+        publish_context: &HashMap<String, String>,
+        mount_flags: &[String],
+    ) -> Result<()> {
         info!("Stage {}", volume_id);
+        ZFS::validate_name(volume_id)?;
         let iscsiadm = self.control.get_iscsiadm().await?;
         let base_iqn = self.options.base_iqn.as_str();
-        let target_portal = self.options.target_portal.as_str();
+        let portal = self.options.portal()?;
+        let lun = match publish_context.get(Self::LUN_CONTEXT_KEY) {
+            Some(l) => Some(l.parse()?),
+            None => None,
+        };
+        let timeout = Duration::from_secs(self.options.device_timeout);
 
         let target_name = iscsiadm.get_target(base_iqn, volume_id);
-        iscsiadm.discovery(target_portal).await?;
-        iscsiadm.login(&target_name, target_portal).await?;
-        let disk_path = iscsiadm.wait_for_disk(&target_name, target_portal).await?;
+        iscsiadm.discovery(&portal).await?;
+        let session = iscsiadm.login(&target_name, &portal).await?;
+        let disk_path = iscsiadm.wait_for_disk(&session, lun, timeout).await?;
 
         let mounts = self.control.mounter().await?;
         let block_device = mounts
@@ -90,7 +208,7 @@ impl StorageModule for ISCSIModule {
         }
 
         mounts
-            .mount(&FilesystemType::Ext4, &disk_path, staging_path)
+            .mount(&FilesystemType::Ext4, &disk_path, staging_path, mount_flags)
             .await?;
 
         Ok(())
@@ -98,12 +216,13 @@ impl StorageModule for ISCSIModule {
 
     async fn unstage(&self, volume_id: &str, staging_path: &str) -> Result<()> {
         info!("Unstaging {}", volume_id);
+        ZFS::validate_name(volume_id)?;
         let control = &self.control;
         control.mounter().await?.umount(&staging_path).await?;
         let iscsiadm = control.get_iscsiadm().await?;
         let target_name = iscsiadm.get_target(&self.options.base_iqn, volume_id);
         iscsiadm
-            .logout(&target_name, &self.options.target_portal)
+            .logout(&target_name, &self.options.portal()?)
             .await?;
         Ok(())
     }
@@ -113,7 +232,7 @@ impl StorageModule for ISCSIModule {
         self.control
             .mounter()
             .await?
-            .mount(&FilesystemType::Bind, staging_path, target_path)
+            .mount(&FilesystemType::Bind, staging_path, target_path, &[])
             .await?;
         Ok(())
     }
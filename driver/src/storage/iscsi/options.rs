@@ -1,37 +1,105 @@
 use super::*;
 use crate::control::ControlModule;
 use crate::Result;
+use regex::Regex;
 use std::collections::HashMap;
 
+lazy_static! {
+    static ref ISCSI_NAME: Regex = Regex::new(
+        r"^(?i:iqn\.\d{4}-\d{2}\.[a-z0-9]([a-z0-9.-]*[a-z0-9])?(:[a-z0-9.:_-]+)?|eui\.[0-9a-f]{16}|naa\.[0-9a-f]{16}([0-9a-f]{16})?)$"
+    )
+    .unwrap();
+    static ref ATTRIBUTE_NAME: Regex = Regex::new(r"^[a-z0-9_]+$").unwrap();
+    static ref ATTRIBUTE_VALUE: Regex = Regex::new(r"^[0-9]+$").unwrap();
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ISCSIOptions {
     pub base_iqn: String,
     pub target_portal: String,
     pub attributes: HashMap<String, String>,
+    /// Attributes of the LIO block backstore, e.g. `emulate_tpu`
+    pub backstore_attributes: HashMap<String, String>,
     pub fs_type: FilesystemType,
+    /// Seconds to wait for the block device to appear after login
+    pub device_timeout: u64,
 }
 
 impl ISCSIOptions {
+    pub(crate) const DEFAULT_DEVICE_TIMEOUT: u64 = 30;
+    const BACKSTORE_ATTR_PREFIX: &'static str = "backstore.attr.";
+
+    /// Check the iSCSI name syntax of RFC 3720, `iqn.yyyy-mm.reversed.domain[:identifier]`,
+    /// `eui.` or `naa.` followed by hex digits
+    pub fn validate_iqn(iqn: &str) -> Result<()> {
+        if ISCSI_NAME.is_match(iqn) {
+            Ok(())
+        } else {
+            Err(AppError::InvalidArgument(format!(
+                "'{}' is not a valid iSCSI name",
+                iqn
+            )))
+        }
+    }
+
+    /// Attributes end up in targetcli commands, LIO attribute names are lowercase words and
+    /// their values integers
+    fn validate_attribute(param: &str, name: &str, value: &str) -> Result<()> {
+        if !ATTRIBUTE_NAME.is_match(name) {
+            return Err(AppError::InvalidArgument(format!(
+                "'{}' is not a valid attribute name",
+                param
+            )));
+        }
+        if !ATTRIBUTE_VALUE.is_match(value) {
+            return Err(AppError::InvalidArgument(format!(
+                "Invalid value '{}' for {}, attribute values are integers",
+                value, param
+            )));
+        }
+        Ok(())
+    }
+
     pub fn new(params: &HashMap<String, String>) -> Result<Self> {
         let base_iqn = params
             .get("baseIqn")
             .ok_or_else(|| AppError::Generic(format!("Base IQN is required!")))?
             .to_string();
+        Self::validate_iqn(&base_iqn)?;
 
         let target_portal = params
             .get("targetPortal")
             .ok_or_else(|| AppError::Generic(format!("Target Portal is required!")))?
             .to_string();
+        target_portal.parse::<Portal>()?;
 
         let fs_type = params
             .get("fsType")
             .map(|fs_str| FilesystemType::from(fs_str.as_str()))
             .unwrap_or(FilesystemType::Ext4);
 
+        let device_timeout = match params.get("deviceTimeout") {
+            Some(t) => t
+                .parse()
+                .map_err(|_| AppError::Generic(format!("Invalid device timeout '{}'", t)))?,
+            None => Self::DEFAULT_DEVICE_TIMEOUT,
+        };
+
         let mut attributes: HashMap<String, String> = Default::default();
         for (k, v) in params.iter() {
             if k.starts_with("attr.") {
-                attributes.insert(k.to_string().split_off(5), v.to_string());
+                let name = k.to_string().split_off(5);
+                Self::validate_attribute(k, &name, v)?;
+                attributes.insert(name, v.to_string());
+            }
+        }
+
+        let mut backstore_attributes: HashMap<String, String> = Default::default();
+        for (k, v) in params.iter() {
+            if k.starts_with(Self::BACKSTORE_ATTR_PREFIX) {
+                let name = k.to_string().split_off(Self::BACKSTORE_ATTR_PREFIX.len());
+                Self::validate_attribute(k, &name, v)?;
+                backstore_attributes.insert(name, v.to_string());
             }
         }
 
@@ -39,9 +107,52 @@ impl ISCSIOptions {
             base_iqn,
             target_portal,
             attributes,
+            backstore_attributes,
             fs_type,
+            device_timeout,
         })
     }
+
+    /// Backstore attributes with defaults applied, sparse zvols get UNMAP support so
+    /// that space freed by the initiator is returned to the pool. The write cache is left to
+    /// LIO unless `backstore.attr.emulate_write_cache` is set, advertising one the zvol does not
+    /// flush safely would risk data on power loss.
+    pub fn backstore_attributes(&self, sparse: bool) -> HashMap<String, String> {
+        let mut result = HashMap::new();
+        if sparse {
+            result.insert("emulate_tpu".to_string(), "1".to_string());
+            result.insert("emulate_tpws".to_string(), "1".to_string());
+        }
+        result.extend(self.backstore_attributes.clone());
+        result
+    }
+
+    /// The parameters that `new` reads back into these options
+    pub fn params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("baseIqn".to_string(), self.base_iqn.to_string());
+        params.insert("targetPortal".to_string(), self.target_portal.to_string());
+        params.insert("fsType".to_string(), self.fs_type.to_string());
+        params.insert("deviceTimeout".to_string(), self.device_timeout.to_string());
+        for (k, v) in self.attributes.iter() {
+            params.insert(format!("attr.{}", k), v.to_string());
+        }
+        for (k, v) in self.backstore_attributes.iter() {
+            params.insert(
+                format!("{}{}", Self::BACKSTORE_ATTR_PREFIX, k),
+                v.to_string(),
+            );
+        }
+        params
+    }
+
+    pub fn target_iqn(&self, volume_id: &str) -> String {
+        format!("{}:{}", self.base_iqn, volume_id.replace("/", "-"))
+    }
+
+    pub fn portal(&self) -> Result<Portal> {
+        self.target_portal.parse()
+    }
 }
 
 impl ControlModule {
@@ -61,3 +172,65 @@ impl ControlModule {
         Ok(self.clone().into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(extra: &[(&str, &str)]) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("baseIqn".to_string(), "iqn.2003-01.org.example".to_string());
+        params.insert("targetPortal".to_string(), "10.0.0.1".to_string());
+        for (k, v) in extra {
+            params.insert(k.to_string(), v.to_string());
+        }
+        params
+    }
+
+    #[test]
+    fn accepts_integer_attributes() {
+        let options = ISCSIOptions::new(&params(&[
+            ("attr.generate_node_acls", "1"),
+            ("backstore.attr.emulate_tpu", "0"),
+        ]))
+        .unwrap();
+        assert_eq!(options.attributes["generate_node_acls"], "1");
+        assert_eq!(options.backstore_attributes["emulate_tpu"], "0");
+    }
+
+    #[test]
+    fn rejects_attributes_that_are_not_lio_words() {
+        for (k, v) in &[
+            ("attr.authentication; exit", "0"),
+            ("attr.Authentication", "0"),
+            ("backstore.attr.", "1"),
+            ("attr.authentication", "0 && reboot"),
+            ("backstore.attr.emulate_tpu", "yes"),
+            ("backstore.attr.emulate_tpu", ""),
+        ] {
+            assert!(
+                ISCSIOptions::new(&params(&[(k, v)])).is_err(),
+                "{}={}",
+                k,
+                v
+            );
+        }
+    }
+
+    #[test]
+    fn backstore_defaults_only_enable_unmap_on_sparse_zvols() {
+        let options = ISCSIOptions::new(&params(&[])).unwrap();
+        assert!(options.backstore_attributes(false).is_empty());
+        let sparse = options.backstore_attributes(true);
+        assert_eq!(sparse["emulate_tpu"], "1");
+        assert_eq!(sparse["emulate_tpws"], "1");
+        assert!(!sparse.contains_key("emulate_write_cache"));
+
+        let options =
+            ISCSIOptions::new(&params(&[("backstore.attr.emulate_write_cache", "1")])).unwrap();
+        assert_eq!(
+            options.backstore_attributes(false)["emulate_write_cache"],
+            "1"
+        );
+    }
+}
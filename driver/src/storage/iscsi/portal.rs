@@ -0,0 +1,167 @@
+use super::*;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// An iSCSI target portal, e.g. `nas.local`, `10.0.0.5:3261` or `[fd00::5]:3260`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Portal {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Portal {
+    pub const DEFAULT_PORT: u16 = 3260;
+
+    /// Resolve the portal host into the addresses an iSCSI session may report
+    pub async fn resolve(&self) -> Result<Vec<IpAddr>> {
+        if let Some(ip) = self.ip() {
+            return Ok(vec![ip]);
+        }
+        let mut result = vec![];
+        for addr in tokio::net::lookup_host((self.host.as_str(), self.port)).await? {
+            if !result.contains(&addr.ip()) {
+                result.push(addr.ip());
+            }
+        }
+        if result.is_empty() {
+            Err(AppError::Generic(format!(
+                "Could not resolve portal host '{}'",
+                self.host
+            )))
+        } else {
+            Ok(result)
+        }
+    }
+
+    /// Format an address in the form iscsiadm and targetcli expect, `[ip]:port` for IPv6
+    pub fn address(ip: &IpAddr, port: u16) -> String {
+        SocketAddr::new(*ip, port).to_string()
+    }
+
+    /// The address of the portal if the host is an IP literal rather than a hostname
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.parse().ok()
+    }
+
+    /// Returns true if a session address belongs to this portal
+    pub fn matches(&self, addrs: &[IpAddr], ip: &str, port: &str) -> bool {
+        // Link-local IPv6 sessions may carry a zone index, e.g. fe80::1%eth0
+        let ip = ip.split('%').next().unwrap_or_default();
+        let ip_match = match ip.parse::<IpAddr>() {
+            Ok(ip) => addrs.contains(&ip),
+            Err(_) => ip == self.host,
+        };
+        ip_match && port.parse::<u16>().ok() == Some(self.port)
+    }
+}
+
+impl FromStr for Portal {
+    type Err = AppError;
+
+    fn from_str(portal: &str) -> Result<Self> {
+        let portal = portal.trim();
+        let invalid = || AppError::Generic(format!("Invalid target portal '{}'", portal));
+        let (host, port) = if let Some(rest) = portal.strip_prefix('[') {
+            // Bracketed IPv6 literal, optionally followed by a port
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, Self::DEFAULT_PORT),
+                _ => (
+                    host,
+                    rest.strip_prefix(':')
+                        .and_then(|p| p.parse().ok())
+                        .ok_or_else(invalid)?,
+                ),
+            }
+        } else if portal.matches(':').count() > 1 {
+            // Bare IPv6 literal, there is no way to specify a port without brackets
+            (portal, Self::DEFAULT_PORT)
+        } else {
+            match portal.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+                None => (portal, Self::DEFAULT_PORT),
+            }
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        if host.contains(':') && host.parse::<Ipv6Addr>().is_err() {
+            return Err(invalid());
+        }
+        Ok(Portal {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for Portal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn portal(host: &str, port: u16) -> Portal {
+        Portal {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parses_hosts_and_ports() {
+        assert_eq!(
+            "nas.local".parse::<Portal>().unwrap(),
+            portal("nas.local", 3260)
+        );
+        assert_eq!(
+            " 10.0.0.5:3261 ".parse::<Portal>().unwrap(),
+            portal("10.0.0.5", 3261)
+        );
+        assert_eq!(
+            "[fd00::5]:3262".parse::<Portal>().unwrap(),
+            portal("fd00::5", 3262)
+        );
+        assert_eq!(
+            "[fd00::5]".parse::<Portal>().unwrap(),
+            portal("fd00::5", 3260)
+        );
+        assert_eq!(
+            "fd00::5".parse::<Portal>().unwrap(),
+            portal("fd00::5", 3260)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_portals() {
+        for p in &[
+            "",
+            ":3260",
+            "nas:port",
+            "nas:70000",
+            "[fd00::5",
+            "[fd00::5]3260",
+            "[]:3260",
+            "[nas:x]",
+        ] {
+            assert!(p.parse::<Portal>().is_err(), "{}", p);
+        }
+    }
+
+    #[test]
+    fn formats_ipv6_in_brackets() {
+        assert_eq!(portal("fd00::5", 3260).to_string(), "[fd00::5]:3260");
+        assert_eq!(portal("10.0.0.5", 3260).to_string(), "10.0.0.5:3260");
+        let ip: IpAddr = "fd00::5".parse().unwrap();
+        assert!(portal("nas", 3260).matches(&[ip], "fd00::5", "3260"));
+        assert!(!portal("nas", 3260).matches(&[ip], "fd00::5", "3261"));
+    }
+}
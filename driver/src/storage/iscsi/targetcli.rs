@@ -1,5 +1,7 @@
 use super::*;
 use regex::Regex;
+use std::collections::HashSet;
+use std::net::IpAddr;
 
 lazy_static! {
     static ref TARGETCLI_PROMPT: Regex = Regex::new("^/(\\S)*>").unwrap();
@@ -7,6 +9,22 @@ lazy_static! {
         Regex::new("o-\\s+(?P<iqn>\\S+)\\s\\.+\\s\\[TPGs: (?P<tpgs>\\d+)]").unwrap();
     static ref TPG_ATTRIBUTE: Regex = Regex::new("(?P<attr>[a-z_0-9]+)=(?P<val>\\d+)").unwrap();
     static ref PARAMETER_SET_SUCCESS: Regex = Regex::new("Parameter \\w+ is now '\\d+'").unwrap();
+    static ref CONFIG_SAVED: Regex = Regex::new("Configuration saved to").unwrap();
+    static ref LUN_CREATED: Regex = Regex::new("Created LUN (?P<lun>\\d+)").unwrap();
+    static ref BACKSTORE_LINE: Regex =
+        Regex::new("o-\\s+(?P<name>\\S+)\\s\\.+\\s\\[(?P<dev>/\\S+)\\s").unwrap();
+    static ref LUN_LINE: Regex =
+        Regex::new("o-\\s+lun(?P<lun>\\d+)\\s\\.+\\s\\[block/(?P<backstore>\\S+)\\s").unwrap();
+    static ref TARGETCLI_ERROR: Regex = Regex::new(
+        "(?m)^(?:No such path|Could not|Cannot|Unable to|Unknown|Invalid|Error|Storage object \\S+ exists|This \\S+ already exists).*$"
+    )
+    .unwrap();
+    static ref ACL_LINE: Regex =
+        Regex::new("o-\\s+(?P<initiator>\\S+)\\s\\.+\\s\\[Mapped LUNs: \\d+]").unwrap();
+    static ref SESSION_LUN: Regex =
+        Regex::new("mapped-lun: \\d+ backstore: block/(?P<backstore>\\S+)").unwrap();
+    static ref PORTAL_LINE: Regex =
+        Regex::new("o-\\s+(?:\\[(?P<ip6>[^\\]]+)\\]|(?P<ip4>[\\d.]+)):(?P<port>\\d+)\\s").unwrap();
 }
 
 pub struct TargetCLI {
@@ -20,6 +38,12 @@ impl TargetCLI {
     pub async fn send_cmd(&mut self, cmd: &str) -> Result<String> {
         self.targetcli.sendline(cmd).await?;
         let (output, _) = self.wait_for_prompt().await?;
+        if let Some(m) = TARGETCLI_ERROR.find(&output) {
+            return Err(AppError::TargetCLI {
+                command: cmd.to_string(),
+                message: m.as_str().trim().to_string(),
+            });
+        }
         Ok(output)
     }
 
@@ -38,12 +62,65 @@ impl TargetCLI {
         Ok(result)
     }
 
+    /// List block backstores by name, along with the device they point at
+    pub async fn list_backstores(&mut self) -> Result<HashMap<String, String>> {
+        let mut result = HashMap::new();
+        let output = self.send_cmd("ls /backstores/block 1").await?;
+        for cap in BACKSTORE_LINE.captures_iter(output.as_str()) {
+            result.insert(cap["name"].to_string(), cap["dev"].to_string());
+        }
+        Ok(result)
+    }
+
+    /// List the LUNs of a target along with the name of the block backstore they map
+    pub async fn list_luns(&mut self, iqn: &str) -> Result<Vec<(u32, String)>> {
+        let mut result = vec![];
+        let output = self
+            .send_cmd(&format!("ls /iscsi/{}/tpg1/luns 1", iqn))
+            .await?;
+        for cap in LUN_LINE.captures_iter(output.as_str()) {
+            result.push((cap["lun"].parse()?, cap["backstore"].to_string()));
+        }
+        debug!("{} has luns: {:?}", iqn, result);
+        Ok(result)
+    }
+
+    /// List the initiators that have an ACL on the target
+    pub async fn list_acls(&mut self, iqn: &str) -> Result<Vec<String>> {
+        let mut result = vec![];
+        let output = self
+            .send_cmd(&format!("ls /iscsi/{}/tpg1/acls 1", iqn))
+            .await?;
+        for cap in ACL_LINE.captures_iter(output.as_str()) {
+            result.push(cap["initiator"].to_string());
+        }
+        debug!("{} has acls: {:?}", iqn, result);
+        Ok(result)
+    }
+
+    /// The block backstores that logged in initiators have mapped
+    pub async fn session_backstores(&mut self) -> Result<HashSet<String>> {
+        let output = self.send_cmd("sessions detail").await?;
+        Ok(SESSION_LUN
+            .captures_iter(output.as_str())
+            .map(|cap| cap["backstore"].to_string())
+            .collect())
+    }
+
+    pub fn zvol_path(volume_id: &str) -> String {
+        format!("/dev/zvol/{}", volume_id)
+    }
+
+    pub fn backstore_name(volume_id: &str) -> String {
+        format!("k8s-{}", volume_id.replace("/", "-"))
+    }
+
     pub async fn create_backstore(&mut self, volume_id: &str) -> Result<String> {
-        let normalized_id = volume_id.replace("/", "-");
-        let backstore_name = format!("k8s-{}", normalized_id);
+        let backstore_name = Self::backstore_name(volume_id);
         let cmd = format!(
-            "/backstores/block create {} /dev/zvol/{}",
-            backstore_name, volume_id
+            "/backstores/block create {} {}",
+            backstore_name,
+            Self::zvol_path(volume_id)
         );
         self.send_cmd(&cmd).await?;
         Ok(backstore_name)
@@ -57,11 +134,54 @@ impl TargetCLI {
         Ok(device_iqn)
     }
 
-    pub async fn set_target_backstore(&mut self, iqn: &str, backstore: &str) -> Result<()> {
+    /// Map the backstore into the target, returning the LUN it was assigned
+    pub async fn set_target_backstore(&mut self, iqn: &str, backstore: &str) -> Result<u32> {
         let cmd = format!(
             "/iscsi/{}/tpg1/luns create /backstores/block/{}",
             iqn, backstore
         );
+        let output = self.send_cmd(&cmd).await?;
+        let lun = LUN_CREATED
+            .captures(&output)
+            .ok_or_else(|| AppError::Generic(format!("Failed to map LUN: {}", output.trim())))?;
+        Ok(lun["lun"].parse()?)
+    }
+
+    pub async fn list_portals(&mut self, iqn: &str) -> Result<Vec<(IpAddr, u16)>> {
+        let mut result = vec![];
+        let output = self
+            .send_cmd(&format!("ls /iscsi/{}/tpg1/portals 1", iqn))
+            .await?;
+        for cap in PORTAL_LINE.captures_iter(output.as_str()) {
+            let ip = cap.name("ip6").or_else(|| cap.name("ip4")).unwrap();
+            result.push((ip.as_str().parse()?, cap["port"].parse()?));
+        }
+        debug!("{} has portals: {:?}", iqn, result);
+        Ok(result)
+    }
+
+    /// Make sure the target listens on the portal, creating a portal on the IP literal if needed
+    pub async fn ensure_portal(&mut self, iqn: &str, portal: &Portal) -> Result<()> {
+        let wanted = portal.ip();
+        let listening = self.list_portals(iqn).await?.iter().any(|(ip, port)| {
+            *port == portal.port
+                && match wanted {
+                    Some(w) => *ip == w || (ip.is_unspecified() && ip.is_ipv6() == w.is_ipv6()),
+                    None => true,
+                }
+        });
+        if listening {
+            return Ok(());
+        }
+
+        let ip_address = wanted
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "0.0.0.0".into());
+        info!("Creating portal {} on {}", portal, iqn);
+        let cmd = format!(
+            "/iscsi/{}/tpg1/portals create ip_address={} ip_port={}",
+            iqn, ip_address, portal.port
+        );
         self.send_cmd(&cmd).await?;
         Ok(())
     }
@@ -91,6 +211,65 @@ impl TargetCLI {
         }
     }
 
+    pub async fn get_backstore_attributes(
+        &mut self,
+        backstore: &str,
+    ) -> Result<HashMap<String, i64>> {
+        let mut result = HashMap::new();
+        let cmd = format!("/backstores/block/{} get attribute", backstore);
+        let output = self.send_cmd(&cmd).await?;
+        for cap in TPG_ATTRIBUTE.captures_iter(output.as_str()) {
+            result.insert(cap["attr"].to_string(), cap["val"].parse()?);
+        }
+        debug!("{} has attributes: {:?}", backstore, result);
+        Ok(result)
+    }
+
+    pub async fn set_backstore_attribute(
+        &mut self,
+        backstore: &str,
+        attr: &str,
+        val: &str,
+    ) -> Result<()> {
+        let cmd = format!(
+            "/backstores/block/{0} set attribute {1}={2}",
+            backstore, attr, val
+        );
+        let output = self.send_cmd(cmd.as_str()).await?;
+        if !PARAMETER_SET_SUCCESS.is_match(&output) {
+            Err(AppError::Generic(format!(
+                "Failed to set backstore parameter {}!",
+                attr
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn delete_target(&mut self, iqn: &str) -> Result<()> {
+        self.send_cmd(&format!("/iscsi delete {}", iqn)).await?;
+        Ok(())
+    }
+
+    pub async fn delete_backstore(&mut self, backstore: &str) -> Result<()> {
+        self.send_cmd(&format!("/backstores/block delete {}", backstore))
+            .await?;
+        Ok(())
+    }
+
+    /// Persist the running LIO configuration so targets survive a reboot of the server
+    pub async fn save_config(&mut self) -> Result<()> {
+        let output = self.send_cmd("saveconfig").await?;
+        if !CONFIG_SAVED.is_match(&output) {
+            Err(AppError::Generic(format!(
+                "Failed to save targetcli configuration: {}",
+                output.trim()
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
     pub async fn close(mut self) -> Result<()> {
         self.targetcli.sendline("exit").await?;
         self.targetcli.wait_for_completion().await?;
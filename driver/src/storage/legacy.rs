@@ -0,0 +1,194 @@
+//! Layouts of the records written before the metadata envelope was introduced. Those records
+//! are bincode, which has no field names, so they can only be read with the exact layout they
+//! were written with. These types are frozen, never change them.
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum FilesystemTypeV0 {
+    Ext2,
+    Ext3,
+    Ext4,
+    XFS,
+    NFS,
+    ZFS,
+    TmpFs,
+    Bind,
+    Unknown,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct ISCSIOptionsV0 {
+    pub base_iqn: String,
+    pub target_portal: String,
+    pub attributes: HashMap<String, String>,
+    pub fs_type: FilesystemTypeV0,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct NFSOptionsV0 {
+    pub host: String,
+    pub export: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct ZFSOptionsV0 {
+    pub parent_dataset: String,
+    pub attributes: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum StorageInfoV0 {
+    ISCSI {
+        options: ISCSIOptionsV0,
+        zfs: ZFSOptionsV0,
+    },
+    NFS {
+        options: NFSOptionsV0,
+        zfs: ZFSOptionsV0,
+    },
+}
+
+impl From<FilesystemTypeV0> for FilesystemType {
+    fn from(fs_type: FilesystemTypeV0) -> Self {
+        match fs_type {
+            FilesystemTypeV0::Ext2 => FilesystemType::Ext2,
+            FilesystemTypeV0::Ext3 => FilesystemType::Ext3,
+            FilesystemTypeV0::Ext4 => FilesystemType::Ext4,
+            FilesystemTypeV0::XFS => FilesystemType::XFS,
+            FilesystemTypeV0::NFS => FilesystemType::NFS,
+            FilesystemTypeV0::ZFS => FilesystemType::ZFS,
+            FilesystemTypeV0::TmpFs => FilesystemType::TmpFs,
+            FilesystemTypeV0::Bind => FilesystemType::Bind,
+            FilesystemTypeV0::Unknown => FilesystemType::Unknown,
+        }
+    }
+}
+
+impl From<ZFSOptionsV0> for ZFSOptions {
+    fn from(zfs: ZFSOptionsV0) -> Self {
+        ZFSOptions {
+            parent_dataset: zfs.parent_dataset,
+            attributes: zfs.attributes,
+            // Volumes were never deleted before the policy existed
+            reclaim_policy: ReclaimPolicy::Retain,
+        }
+    }
+}
+
+impl From<StorageInfoV0> for StorageInfo {
+    fn from(info: StorageInfoV0) -> Self {
+        match info {
+            StorageInfoV0::ISCSI { options, zfs } => StorageInfo::ISCSI {
+                options: ISCSIOptions {
+                    base_iqn: options.base_iqn,
+                    target_portal: options.target_portal,
+                    attributes: options.attributes,
+                    backstore_attributes: HashMap::new(),
+                    fs_type: options.fs_type.into(),
+                    device_timeout: ISCSIOptions::DEFAULT_DEVICE_TIMEOUT,
+                },
+                zfs: zfs.into(),
+            },
+            StorageInfoV0::NFS { options, zfs } => StorageInfo::NFS {
+                options: NFSOptions {
+                    host: options.host,
+                    export: options.export,
+                },
+                zfs: zfs.into(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MetadataBackend;
+
+    fn fixture() -> StorageInfoV0 {
+        let mut attributes = HashMap::new();
+        attributes.insert("compression".to_string(), "lz4".to_string());
+        StorageInfoV0::ISCSI {
+            options: ISCSIOptionsV0 {
+                base_iqn: "iqn.2003-01.org.example".into(),
+                target_portal: "10.0.0.1:3260".into(),
+                attributes: HashMap::new(),
+                fs_type: FilesystemTypeV0::XFS,
+            },
+            zfs: ZFSOptionsV0 {
+                parent_dataset: "tank/k8s/".into(),
+                attributes,
+            },
+        }
+    }
+
+    #[test]
+    fn decodes_baseline_bincode() {
+        let bytes = bincode::serialize(&fixture()).unwrap();
+        match StorageInfo::decode_legacy(&bytes).unwrap() {
+            StorageInfo::ISCSI { options, zfs } => {
+                assert_eq!(options.base_iqn, "iqn.2003-01.org.example");
+                assert_eq!(options.target_portal, "10.0.0.1:3260");
+                assert!(matches!(options.fs_type, FilesystemType::XFS));
+                assert!(options.backstore_attributes.is_empty());
+                assert_eq!(options.device_timeout, ISCSIOptions::DEFAULT_DEVICE_TIMEOUT);
+                assert_eq!(zfs.parent_dataset, "tank/k8s/");
+                assert_eq!(zfs.attributes["compression"], "lz4");
+                assert_eq!(zfs.reclaim_policy, ReclaimPolicy::Retain);
+            }
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_baseline_nfs_bincode() {
+        let bytes = bincode::serialize(&StorageInfoV0::NFS {
+            options: NFSOptionsV0 {
+                host: "nas".into(),
+                export: "/export".into(),
+            },
+            zfs: ZFSOptionsV0 {
+                parent_dataset: "tank/nfs/".into(),
+                attributes: HashMap::new(),
+            },
+        })
+        .unwrap();
+        match StorageInfo::decode_legacy(&bytes).unwrap() {
+            StorageInfo::NFS { options, zfs } => {
+                assert_eq!(options.host, "nas");
+                assert_eq!(options.export, "/export");
+                assert_eq!(zfs.parent_dataset, "tank/nfs/");
+            }
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn copies_into_the_file_backend() {
+        let dir = std::env::temp_dir().join(format!("metal-csi-legacy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let backend = MetadataBackend::File {
+            path: dir.join("metadata.json"),
+        };
+        let metadata = Metadata::new(&backend, &dir, false).await.unwrap();
+        let bytes = bincode::serialize(&fixture()).unwrap();
+        metadata
+            .set_raw::<StorageInfo>("tank/k8s/pvc-1", bytes)
+            .await
+            .unwrap();
+        match metadata.get::<StorageInfo>("tank/k8s/pvc-1").await.unwrap() {
+            Some(StorageInfo::ISCSI { options, .. }) => {
+                assert_eq!(options.base_iqn, "iqn.2003-01.org.example")
+            }
+            other => panic!("read back {:?}", other),
+        }
+        drop(metadata);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_bincode() {
+        let bytes = bincode::serialize(&fixture()).unwrap();
+        assert!(StorageInfo::decode_legacy(&bytes[..bytes.len() / 2]).is_err());
+    }
+}
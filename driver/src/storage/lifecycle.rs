@@ -0,0 +1,506 @@
+use super::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::sync::{Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// Publish context key naming the node the volume was published to, so that a node can tell
+/// whether it may stage the volume without sharing the controller's metadata
+pub const PUBLISHED_NODE_KEY: &str = "publishedNode";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VolumePhase {
+    Ready,
+    /// Deletion was requested and has not completed
+    Deleting,
+}
+
+/// What the driver did with a volume. The controller and each node record what they do, they
+/// share the record when they share a metadata backend. Times are seconds since the epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolumeState {
+    pub phase: VolumePhase,
+    pub capacity_bytes: i64,
+    pub volume_context: HashMap<String, String>,
+    /// Nodes the volume is published to, with when it was published
+    pub published: BTreeMap<String, u64>,
+    /// Staging path of every node the volume is staged on
+    pub staged: BTreeMap<String, String>,
+    /// Target paths of every node the volume is mounted on
+    pub mounted: BTreeMap<String, BTreeSet<String>>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    /// The last operation on the volume that failed, cleared by the next one that succeeds
+    pub last_error: Option<String>,
+}
+
+impl Storeable for VolumeState {
+    const KEY: &'static str = "VolumeState";
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum VolumeEvent<'a> {
+    Created {
+        capacity_bytes: i64,
+        volume_context: &'a HashMap<String, String>,
+    },
+    Deleted,
+    Published {
+        node: &'a str,
+    },
+    /// An empty node unpublishes the volume from every node
+    Unpublished {
+        node: &'a str,
+    },
+    Staged {
+        node: &'a str,
+        staging_path: &'a str,
+        /// The node named in the publish context, if the controller recorded one
+        published_to: Option<&'a str>,
+    },
+    Unstaged {
+        node: &'a str,
+    },
+    Mounted {
+        node: &'a str,
+        target_path: &'a str,
+    },
+    Unmounted {
+        node: &'a str,
+        target_path: &'a str,
+    },
+}
+
+/// One lock per volume, held by a request for as long as it works on the volume so that
+/// concurrent requests for the same volume don't interleave their operations and records
+#[derive(Debug, Default)]
+pub struct VolumeLocks(Mutex<HashMap<String, Weak<AsyncMutex<()>>>>);
+
+impl VolumeLocks {
+    pub async fn lock(&self, volume_id: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.0.lock().unwrap();
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            match locks.get(volume_id).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(AsyncMutex::new(()));
+                    locks.insert(volume_id.to_string(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+impl VolumeState {
+    fn new(now: u64) -> Self {
+        VolumeState {
+            phase: VolumePhase::Ready,
+            capacity_bytes: 0,
+            volume_context: HashMap::new(),
+            published: BTreeMap::new(),
+            staged: BTreeMap::new(),
+            mounted: BTreeMap::new(),
+            created_at: now,
+            updated_at: now,
+            last_error: None,
+        }
+    }
+
+    /// Whether the event may happen to the volume. A volume without a record predates them
+    /// and is taken to be in whatever state the event needs.
+    fn check(state: Option<&Self>, volume_id: &str, event: &VolumeEvent) -> Result<()> {
+        let state = match state {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let refuse = |reason: String| {
+            Err(AppError::FailedPrecondition(format!(
+                "Volume '{}' {}",
+                volume_id, reason
+            )))
+        };
+        match *event {
+            VolumeEvent::Deleted if !state.published.is_empty() => refuse(format!(
+                "is still published to {:?}",
+                state.published.keys().collect::<Vec<_>>()
+            )),
+            VolumeEvent::Published { .. } | VolumeEvent::Staged { .. }
+                if state.phase == VolumePhase::Deleting =>
+            {
+                refuse("is being deleted".into())
+            }
+            VolumeEvent::Staged {
+                node,
+                published_to: Some(published_to),
+                ..
+            } if published_to != node => refuse(format!("is published to {}", published_to)),
+            VolumeEvent::Staged {
+                node,
+                published_to: None,
+                ..
+            } if !state.published.is_empty() && !state.published.contains_key(node) => {
+                refuse(format!("is not published to {}", node))
+            }
+            VolumeEvent::Unstaged { node }
+                if state
+                    .mounted
+                    .get(node)
+                    .map(|m| !m.is_empty())
+                    .unwrap_or(false) =>
+            {
+                refuse(format!("is still mounted at {:?}", state.mounted[node]))
+            }
+            VolumeEvent::Mounted { node, .. } if !state.staged.contains_key(node) => {
+                refuse(format!("is not staged on {}", node))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn apply(&mut self, event: &VolumeEvent, now: u64) {
+        match *event {
+            VolumeEvent::Created {
+                capacity_bytes,
+                volume_context,
+            } => {
+                self.phase = VolumePhase::Ready;
+                self.capacity_bytes = capacity_bytes;
+                self.volume_context = volume_context.clone();
+            }
+            VolumeEvent::Deleted => self.phase = VolumePhase::Deleting,
+            VolumeEvent::Published { node } => {
+                self.published.entry(node.to_string()).or_insert(now);
+            }
+            VolumeEvent::Unpublished { node: "" } => self.published.clear(),
+            VolumeEvent::Unpublished { node } => {
+                self.published.remove(node);
+            }
+            VolumeEvent::Staged {
+                node,
+                staging_path,
+                published_to,
+            } => {
+                if published_to.is_some() {
+                    self.published.entry(node.to_string()).or_insert(now);
+                }
+                self.staged
+                    .insert(node.to_string(), staging_path.to_string());
+            }
+            VolumeEvent::Unstaged { node } => {
+                self.staged.remove(node);
+                self.mounted.remove(node);
+            }
+            VolumeEvent::Mounted { node, target_path } => {
+                self.mounted
+                    .entry(node.to_string())
+                    .or_default()
+                    .insert(target_path.to_string());
+            }
+            VolumeEvent::Unmounted { node, target_path } => {
+                if let Some(paths) = self.mounted.get_mut(node) {
+                    paths.remove(target_path);
+                    if paths.is_empty() {
+                        self.mounted.remove(node);
+                    }
+                }
+            }
+        }
+        self.updated_at = now;
+        self.last_error = None;
+    }
+
+    /// Run an operation on a volume if the event is allowed, recording the event when the
+    /// operation succeeds and its error otherwise. The record is removed once the volume is
+    /// deleted, a failed deletion leaves it as it was.
+    pub async fn track<T, F>(
+        metadata: &Metadata,
+        volume_id: &str,
+        event: VolumeEvent<'_>,
+        operation: F,
+    ) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let state = metadata.get::<VolumeState>(volume_id).await?;
+        Self::check(state.as_ref(), volume_id, &event)?;
+        if let VolumeEvent::Deleted = event {
+            // Deleting is recorded up front, publishing is refused from then on
+            let now = now()?;
+            let mut deleting = state.clone().unwrap_or_else(|| Self::new(now));
+            deleting.apply(&event, now);
+            metadata.set(volume_id, deleting).await?;
+        }
+
+        let result = match operation.await {
+            Ok(result) => result,
+            Err(e) => {
+                if let VolumeEvent::Deleted = event {
+                    Self::restore(metadata, volume_id, state).await;
+                }
+                Self::record_error(metadata, volume_id, &e).await;
+                return Err(e);
+            }
+        };
+
+        if let VolumeEvent::Deleted = event {
+            metadata.delete::<VolumeState>(volume_id).await?;
+            return Ok(result);
+        }
+        let now = now()?;
+        let mut state = metadata
+            .get::<VolumeState>(volume_id)
+            .await?
+            .unwrap_or_else(|| Self::new(now));
+        state.apply(&event, now);
+        metadata.set(volume_id, state).await?;
+        Ok(result)
+    }
+
+    /// Create the records of volumes that were created before volume states were recorded, from
+    /// their storage info and publication. Their capacity is not known.
+    pub async fn backfill(metadata: &Metadata) -> Result<usize> {
+        let now = now()?;
+        let mut count = 0;
+        for (volume_id, info) in metadata.list::<StorageInfo>().await? {
+            if metadata.get::<VolumeState>(&volume_id).await?.is_some() {
+                continue;
+            }
+            let mut state = Self::new(now);
+            state.volume_context = info.to_params();
+            if let Some(publication) = metadata.get::<Publication>(&volume_id).await? {
+                if let Some(node) = publication.publish_context.get(PUBLISHED_NODE_KEY) {
+                    state.published.insert(node.to_string(), now);
+                }
+            }
+            metadata.set(&volume_id, state).await?;
+            count += 1;
+        }
+        if count > 0 {
+            info!("Recorded the state of {} existing volumes", count);
+        }
+        Ok(count)
+    }
+
+    /// Record an event that already happened
+    pub async fn record(
+        metadata: &Metadata,
+        volume_id: &str,
+        event: VolumeEvent<'_>,
+    ) -> Result<()> {
+        Self::track(metadata, volume_id, event, async { Ok(()) }).await
+    }
+
+    /// Put back the record a volume had before its deletion was attempted
+    async fn restore(metadata: &Metadata, volume_id: &str, state: Option<Self>) {
+        let result = match state {
+            Some(state) => metadata.set(volume_id, state).await,
+            None => metadata.delete::<VolumeState>(volume_id).await,
+        };
+        if let Err(e) = result {
+            warn!(
+                "Could not restore the state of volume '{}': {}",
+                volume_id, e
+            );
+        }
+    }
+
+    /// Keep the error of a failed operation on the record of the volume, if it has one
+    pub async fn record_error(metadata: &Metadata, volume_id: &str, error: &AppError) {
+        let result = async {
+            if let Some(mut state) = metadata.get::<VolumeState>(volume_id).await? {
+                state.updated_at = now()?;
+                state.last_error = Some(error.to_string());
+                metadata.set(volume_id, state).await?;
+            }
+            Ok::<_, AppError>(())
+        };
+        if let Err(e) = result.await {
+            warn!(
+                "Could not record the error of volume '{}': {}",
+                volume_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> VolumeState {
+        VolumeState::new(0)
+    }
+
+    fn allowed(state: &VolumeState, event: VolumeEvent) -> bool {
+        VolumeState::check(Some(state), "vol", &event).is_ok()
+    }
+
+    #[test]
+    fn volumes_without_record_allow_everything() {
+        assert!(VolumeState::check(None, "vol", &VolumeEvent::Deleted).is_ok());
+        assert!(VolumeState::check(
+            None,
+            "vol",
+            &VolumeEvent::Mounted {
+                node: "a",
+                target_path: "/t"
+            }
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn published_volumes_are_not_deleted() {
+        let mut state = state();
+        state.apply(&VolumeEvent::Published { node: "a" }, 1);
+        let error = VolumeState::check(Some(&state), "vol", &VolumeEvent::Deleted).unwrap_err();
+        assert!(matches!(error, AppError::FailedPrecondition(_)));
+        state.apply(&VolumeEvent::Unpublished { node: "a" }, 2);
+        assert!(allowed(&state, VolumeEvent::Deleted));
+    }
+
+    #[test]
+    fn unpublishing_from_every_node() {
+        let mut state = state();
+        state.apply(&VolumeEvent::Published { node: "a" }, 1);
+        state.apply(&VolumeEvent::Published { node: "b" }, 1);
+        state.apply(&VolumeEvent::Unpublished { node: "" }, 2);
+        assert!(state.published.is_empty());
+        assert!(allowed(&state, VolumeEvent::Deleted));
+    }
+
+    #[test]
+    fn deleting_volumes_are_not_published_or_staged() {
+        let mut state = state();
+        state.apply(&VolumeEvent::Deleted, 1);
+        assert!(!allowed(&state, VolumeEvent::Published { node: "a" }));
+        assert!(!allowed(
+            &state,
+            VolumeEvent::Staged {
+                node: "a",
+                staging_path: "/s",
+                published_to: None
+            }
+        ));
+    }
+
+    #[test]
+    fn staging_follows_publication() {
+        let mut state = state();
+        let staged = |node, published_to| VolumeEvent::Staged {
+            node,
+            staging_path: "/s",
+            published_to,
+        };
+        assert!(allowed(&state, staged("a", None)));
+        assert!(!allowed(&state, staged("a", Some("b"))));
+        state.apply(&VolumeEvent::Published { node: "b" }, 1);
+        assert!(!allowed(&state, staged("a", None)));
+        assert!(allowed(&state, staged("b", None)));
+        assert!(allowed(&state, staged("b", Some("b"))));
+    }
+
+    #[test]
+    fn mounts_need_a_stage_and_block_unstaging() {
+        let mut state = state();
+        let mounted = VolumeEvent::Mounted {
+            node: "a",
+            target_path: "/t",
+        };
+        assert!(!allowed(&state, mounted));
+        state.apply(
+            &VolumeEvent::Staged {
+                node: "a",
+                staging_path: "/s",
+                published_to: None,
+            },
+            1,
+        );
+        assert!(allowed(&state, mounted));
+        state.apply(&mounted, 2);
+        assert!(!allowed(&state, VolumeEvent::Unstaged { node: "a" }));
+        assert!(allowed(&state, VolumeEvent::Unstaged { node: "b" }));
+        state.apply(
+            &VolumeEvent::Unmounted {
+                node: "a",
+                target_path: "/t",
+            },
+            3,
+        );
+        assert!(state.mounted.is_empty());
+        assert!(allowed(&state, VolumeEvent::Unstaged { node: "a" }));
+    }
+
+    #[tokio::test]
+    async fn failed_deletions_leave_volumes_usable() {
+        let metadata = Metadata::temporary().unwrap();
+        VolumeState::record(&metadata, "vol", VolumeEvent::Published { node: "a" })
+            .await
+            .unwrap();
+        VolumeState::record(&metadata, "vol", VolumeEvent::Unpublished { node: "a" })
+            .await
+            .unwrap();
+        let deleted = VolumeState::track(&metadata, "vol", VolumeEvent::Deleted, async {
+            Err::<(), _>(AppError::Generic("dataset is busy".into()))
+        })
+        .await;
+        assert!(deleted.is_err());
+
+        let state = metadata.get::<VolumeState>("vol").await.unwrap().unwrap();
+        assert_eq!(state.phase, VolumePhase::Ready);
+        assert_eq!(state.last_error.as_deref(), Some("dataset is busy"));
+        VolumeState::record(&metadata, "vol", VolumeEvent::Published { node: "a" })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn backfills_volumes_without_state() {
+        let metadata = Metadata::temporary().unwrap();
+        let mut params = HashMap::new();
+        params.insert("type".to_string(), "nfs".to_string());
+        params.insert("host".to_string(), "nas".to_string());
+        params.insert("export".to_string(), "/export".to_string());
+        params.insert("zfs.parentDataset".to_string(), "tank/nfs/".to_string());
+        let info = StorageInfo::from_params(&params).unwrap();
+        metadata.set("tank/nfs/old", info).await.unwrap();
+        let mut publish_context = HashMap::new();
+        publish_context.insert(PUBLISHED_NODE_KEY.to_string(), "node-1".to_string());
+        metadata
+            .set("tank/nfs/old", Publication { publish_context })
+            .await
+            .unwrap();
+
+        assert_eq!(VolumeState::backfill(&metadata).await.unwrap(), 1);
+        let state = metadata
+            .get::<VolumeState>("tank/nfs/old")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.volume_context["host"], "nas");
+        assert!(state.published.contains_key("node-1"));
+        assert_eq!(VolumeState::backfill(&metadata).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn volume_locks_serialize_per_volume() {
+        let locks = VolumeLocks::default();
+        let guard = locks.lock("a").await;
+        assert!(locks.0.lock().unwrap()["a"]
+            .upgrade()
+            .unwrap()
+            .try_lock()
+            .is_err());
+        let _other = locks.lock("b").await;
+        drop(guard);
+        let _again = locks.lock("a").await;
+    }
+}
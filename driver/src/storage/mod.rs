@@ -1,7 +1,10 @@
 pub use self::filesystem::FilesystemType;
-use self::iscsi::{ISCSIModule, ISCSIOptions};
+pub use self::gc::*;
+use self::iscsi::{ISCSIModule, ISCSIOptions, Portal};
+pub use self::lifecycle::*;
 use self::nfs::{NFSModule, NFSOptions};
-use self::zfs::ZFSOptions;
+use self::zfs::{ZFSDataset, ZFSOptions, ZFS};
+use crate::config::{Configuration, InitiatorIqnMode, ReclaimPolicy};
 use crate::control::ControlModule;
 use crate::error::AppError;
 use crate::metadata::{Metadata, Storeable};
@@ -12,9 +15,13 @@ use std::fmt::Debug;
 use std::sync::Arc;
 
 mod filesystem;
+mod gc;
 mod iscsi;
+mod legacy;
+mod lifecycle;
 mod mounter;
 mod nfs;
+mod recovery;
 mod zfs;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,12 +39,174 @@ pub enum StorageInfo {
 impl Storeable for StorageInfo {
     const KEY: &'static str = "StorageInfo";
 
-    fn into_bytes(self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(&self)?)
+    fn decode_legacy(bytes: &[u8]) -> Result<Self> {
+        let info: legacy::StorageInfoV0 =
+            bincode::deserialize(bytes).map_err(|e| AppError::Generic(e.to_string()))?;
+        Ok(info.into())
+    }
+}
+
+impl StorageInfo {
+    /// Prefix of the ZFS user properties that describe a volume on its dataset
+    const PROPERTY_PREFIX: &'static str = "metal-csi:";
+    /// Set on every dataset created by the driver
+    pub const TYPE_PROPERTY: &'static str = "metal-csi:type";
+    /// When the volume of a retained dataset was deleted, in seconds since the epoch
+    pub const DELETED_PROPERTY: &'static str = "metal-csi:deleted";
+    /// User property names of parameters, ZFS only allows lowercase property names
+    const PROPERTY_NAMES: &'static [(&'static str, &'static str)] = &[
+        ("type", "type"),
+        ("baseIqn", "iqn"),
+        ("targetPortal", "portal"),
+        ("fsType", "fstype"),
+        ("deviceTimeout", "device-timeout"),
+        ("reclaimPolicy", "reclaim-policy"),
+        ("zfs.parentDataset", "parent-dataset"),
+        ("host", "nfs-host"),
+        ("export", "nfs-export"),
+    ];
+    /// Parameters with these prefixes keep their name as a user property
+    const PROPERTY_MAPS: &'static [&'static str] = &["attr.", "backstore.attr.", "zfs.attr."];
+
+    /// Parse volume parameters, StorageClass parameters need the configured defaults merged first
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self> {
+        match params.get("type").map(|s| s.as_str()) {
+            Some("zfs-iscsi") | Some("iscsi") => {
+                let options = ISCSIOptions::new(params)?;
+                let zfs = ZFSOptions::new(params)?;
+                Ok(StorageInfo::ISCSI { options, zfs })
+            }
+            Some("zfs-nfs") | Some("nfs") => {
+                let options = NFSOptions::new(params)?;
+                let zfs = ZFSOptions::new(params)?;
+                Ok(StorageInfo::NFS { options, zfs })
+            }
+            Some(s) => Err(AppError::Generic(format!(
+                "'{}' is an unknown storage type!",
+                s
+            ))),
+            None => Err(AppError::Generic(format!(
+                "Storage type was not specified!"
+            ))),
+        }
     }
 
-    fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
-        Ok(bincode::deserialize(&bytes)?)
+    /// The parameters `from_params` reads back into this storage info, handed to the node in
+    /// the volume and publish contexts
+    pub fn to_params(&self) -> HashMap<String, String> {
+        let (storage_type, mut params, zfs) = match self {
+            StorageInfo::ISCSI { options, zfs } => ("iscsi", options.params(), zfs),
+            StorageInfo::NFS { options, zfs } => ("nfs", options.params(), zfs),
+        };
+        params.extend(zfs.params());
+        params.insert("type".to_string(), storage_type.to_string());
+        params
+    }
+
+    /// ZFS user properties recorded on the dataset of the volume, `metal-csi:type`,
+    /// `metal-csi:iqn`, ...
+    pub fn properties(&self) -> HashMap<String, String> {
+        self.to_params()
+            .into_iter()
+            .filter_map(|(key, val)| {
+                let name = Self::PROPERTY_NAMES
+                    .iter()
+                    .find(|(param, _)| *param == key)
+                    .map(|(_, name)| name.to_string())
+                    .or_else(|| {
+                        Self::PROPERTY_MAPS
+                            .iter()
+                            .find(|prefix| key.starts_with(*prefix))
+                            .map(|_| key.to_string())
+                    })?;
+                Some((format!("{}{}", Self::PROPERTY_PREFIX, name), val))
+            })
+            .collect()
+    }
+
+    /// Rebuild the storage info from the user properties of a dataset, datasets without a
+    /// `metal-csi:type` property predate them
+    pub fn from_properties(dataset: &ZFSDataset) -> Result<Option<Self>> {
+        let properties = dataset.user_properties(Self::PROPERTY_PREFIX);
+        if !properties.contains_key("type") {
+            return Ok(None);
+        }
+        let params = properties
+            .into_iter()
+            .filter_map(|(name, val)| {
+                let key = Self::PROPERTY_NAMES
+                    .iter()
+                    .find(|(_, n)| *n == name)
+                    .map(|(param, _)| param.to_string())
+                    .or_else(|| {
+                        Self::PROPERTY_MAPS
+                            .iter()
+                            .find(|prefix| name.starts_with(*prefix))
+                            .map(|_| name.to_string())
+                    })?;
+                Some((key, val))
+            })
+            .collect();
+        Ok(Some(Self::from_params(&params)?))
+    }
+}
+
+/// Recorded by the controller while a volume is published
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Publication {
+    pub publish_context: HashMap<String, String>,
+}
+
+impl Storeable for Publication {
+    const KEY: &'static str = "Publication";
+}
+
+/// Put the keys returned by a publish into the recorded publish context, which also holds the
+/// storage parameters and the published node. Returns the keys whose value changed.
+fn merge_publish_context(
+    recorded: &mut HashMap<String, String>,
+    published: HashMap<String, String>,
+) -> Vec<String> {
+    let mut changed = vec![];
+    for (key, val) in published {
+        if recorded.get(&key) != Some(&val) {
+            changed.push(key.clone());
+            recorded.insert(key, val);
+        }
+    }
+    changed.sort();
+    changed
+}
+
+/// Recorded by the node for every volume it stages, so that the stage and the mounts of the
+/// volume can be redone after a reboot or crash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Staging {
+    pub node_id: String,
+    pub volume_id: String,
+    pub staging_path: String,
+    pub publish_context: HashMap<String, String>,
+    pub mount_flags: Vec<String>,
+    /// Where the volume is bind mounted for pods
+    pub target_paths: Vec<String>,
+    pub state: StagingState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StagingState {
+    /// Stage was requested but has not completed
+    Staging,
+    Staged,
+}
+
+impl Storeable for Staging {
+    const KEY: &'static str = "Staging";
+}
+
+impl Staging {
+    /// Records are kept per node in case several nodes share a metadata backend
+    pub fn key(node_id: &str, volume_id: &str) -> String {
+        format!("{}:{}", node_id, volume_id)
     }
 }
 
@@ -45,12 +214,52 @@ impl Storeable for StorageInfo {
 pub struct Storage(Arc<Box<dyn StorageModule>>);
 
 impl Storage {
+    /// Values of the StorageClass `type` parameter
+    const STORAGE_TYPES: &'static [&'static str] = &["zfs-iscsi", "iscsi", "zfs-nfs", "nfs"];
+
+    /// Check the storage defaults of the configuration, returning every problem found
+    pub fn validate_config(config: &Configuration) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some(storage_type) = &config.driver.storage_class {
+            if !Self::STORAGE_TYPES.contains(&storage_type.as_str()) {
+                problems.push(format!(
+                    "driver.storage_class: '{}' is not one of {:?}",
+                    storage_type,
+                    Self::STORAGE_TYPES
+                ));
+            }
+        }
+        if let Some(base_iqn) = &config.iscsi.base_iqn {
+            if let Err(e) = ISCSIOptions::validate_iqn(base_iqn) {
+                problems.push(format!("iscsi.base_iqn: {}", e));
+            }
+        }
+        if let Some(target_portal) = &config.iscsi.target_portal {
+            if let Err(e) = target_portal.parse::<Portal>() {
+                problems.push(format!("iscsi.target_portal: {}", e));
+            }
+        }
+        if let Some(parent_dataset) = &config.zfs.parent_dataset {
+            if let Err(e) = ZFS::validate_name(parent_dataset.trim_end_matches('/')) {
+                problems.push(format!("zfs.parent_dataset: {}", e));
+            }
+        }
+        if let InitiatorIqnMode::Fixed { iqn } = &config.node.initiator_iqn_mode {
+            if let Err(e) = ISCSIOptions::validate_iqn(iqn) {
+                problems.push(format!("node.initiator_iqn_mode.iqn: {}", e));
+            }
+        }
+        problems
+    }
+
     pub async fn new_from_params_secrets(
         params: &HashMap<String, String>,
         secrets: &HashMap<String, String>,
+        config: &Configuration,
+        metadata: &Metadata,
     ) -> Result<Self> {
-        let control = ControlModule::from_map(secrets)?;
-        let storage_info = Self::get_storage_info_from_params(params).await?;
+        let control = ControlModule::from_map(secrets, config, metadata)?;
+        let storage_info = Self::get_storage_info_from_params(params, config).await?;
         Self::new_from_storage_info(storage_info, control).await
     }
 
@@ -58,10 +267,11 @@ impl Storage {
         params: &HashMap<String, String>,
         secrets: &HashMap<String, String>,
         volume_id: &str,
+        config: &Configuration,
         metadata: &Metadata,
     ) -> Result<Self> {
-        let control = ControlModule::from_map(secrets)?;
-        let storage_info = Self::get_storage_info_from_params(params).await?;
+        let control = ControlModule::from_map(secrets, config, metadata)?;
+        let storage_info = Self::get_storage_info_from_params(params, config).await?;
         metadata.set(volume_id, storage_info.clone()).await?;
         Self::new_from_storage_info(storage_info, control).await
     }
@@ -70,9 +280,10 @@ impl Storage {
         params: &HashMap<String, String>,
         control: ControlModule,
         volume_id: &str,
+        config: &Configuration,
         metadata: &Metadata,
     ) -> Result<Self> {
-        let storage_info = Self::get_storage_info_from_params(params).await?;
+        let storage_info = Self::get_storage_info_from_params(params, config).await?;
         metadata.set(volume_id, storage_info.clone()).await?;
         Self::new_from_storage_info(storage_info, control).await
     }
@@ -83,44 +294,113 @@ impl Storage {
         metadata: &Metadata,
     ) -> Result<Self> {
         Self::new_from_storage_info(
-            Self::get_storage_info_from_volume_id(volume_id, metadata).await?,
+            Self::get_storage_info_from_volume_id(volume_id, &control, metadata).await?,
             control,
         )
         .await
     }
 
+    /// Look a volume up on the storage server, the properties of its dataset are read first so
+    /// that any controller can act on it, the local database covers volumes created before
+    /// they were recorded
     pub async fn get_storage_info_from_volume_id(
         volume_id: &str,
+        control: &ControlModule,
         metadata: &Metadata,
     ) -> Result<StorageInfo> {
+        if let Some(dataset) = control.zfs().await?.get_dataset(volume_id).await? {
+            if let Some(storage_info) = StorageInfo::from_properties(&dataset)? {
+                return Ok(storage_info);
+            }
+        }
         Ok(metadata
             .get(volume_id)
             .await?
             .ok_or_else(|| AppError::Generic(format!("No metadata for specified volume ID!")))?)
     }
 
+    /// The storage info the node recorded when it staged or mounted the volume
+    pub async fn get_node_storage_info(
+        volume_id: &str,
+        metadata: &Metadata,
+    ) -> Result<Option<StorageInfo>> {
+        metadata.get(volume_id).await
+    }
+
+    /// Unstage a volume the node has no record of, e.g. after a reinstall. Whatever is mounted
+    /// at the staging path is unmounted and the iSCSI session of its device is logged out.
+    pub async fn unstage_path(control: ControlModule, staging_path: &str) -> Result<()> {
+        let mounter = control.mounter().await?;
+        let mount = match mounter.get_mount(staging_path).await? {
+            Some(m) => m,
+            None => {
+                info!("Nothing is mounted at {}, nothing to unstage", staging_path);
+                return Ok(());
+            }
+        };
+        mounter.umount(staging_path).await?;
+        if let Some(disk) = mount.source.strip_prefix("/dev/") {
+            control.get_iscsiadm().await?.logout_disk(disk).await?;
+        }
+        Ok(())
+    }
+
+    /// Unmount a volume the node has no record of
+    pub async fn unmount_path(control: ControlModule, target_path: &str) -> Result<()> {
+        control.mounter().await?.umount(target_path).await
+    }
+
+    /// Parse StorageClass parameters, falling back to the configured defaults
     pub async fn get_storage_info_from_params(
         params: &HashMap<String, String>,
+        config: &Configuration,
     ) -> Result<StorageInfo> {
-        match params.get("type").map(|s| s.as_str()) {
-            Some("zfs-iscsi") => {
-                let options = ISCSIOptions::new(params)?;
-                let zfs = ZFSOptions::new(params)?;
-                Ok(StorageInfo::ISCSI { options, zfs })
+        StorageInfo::from_params(&config.parameters(params))
+    }
+
+    /// Bring the iSCSI targets of published volumes back in line with their records, e.g. after
+    /// a reboot that lost an unsaved LIO configuration. Publishing is idempotent and checks the
+    /// backstore, the LUN mapping, the portal and the attributes, recreating what is missing.
+    pub async fn reconcile(
+        control: ControlModule,
+        metadata: &Metadata,
+        volume_locks: &VolumeLocks,
+    ) -> Result<()> {
+        let publications = metadata.list::<Publication>().await?;
+        if publications.is_empty() {
+            return Ok(());
+        }
+        info!("Reconciling {} published volumes", publications.len());
+
+        for (volume_id, publication) in publications {
+            let _lock = volume_locks.lock(&volume_id).await;
+            let result: Result<()> = async {
+                let storage_info =
+                    Self::get_storage_info_from_volume_id(&volume_id, &control, metadata).await?;
+                if !matches!(storage_info, StorageInfo::ISCSI { .. }) {
+                    return Ok(());
+                }
+                let storage = Self::new_from_storage_info(storage_info, control.clone()).await?;
+                let mut publish_context = publication.publish_context;
+                let changed =
+                    merge_publish_context(&mut publish_context, storage.publish(&volume_id).await?);
+                if !changed.is_empty() {
+                    warn!(
+                        "Publish context of volume '{}' changed: {:?}",
+                        volume_id, changed
+                    );
+                    metadata
+                        .set(&volume_id, Publication { publish_context })
+                        .await?;
+                }
+                Ok(())
             }
-            Some("zfs-nfs") => {
-                let options = NFSOptions::new(params)?;
-                let zfs = ZFSOptions::new(params)?;
-                Ok(StorageInfo::NFS { options, zfs })
+            .await;
+            if let Err(e) = result {
+                error!("Cannot reconcile volume '{}': {}", volume_id, e);
             }
-            Some(s) => Err(AppError::Generic(format!(
-                "'{}' is an unknown storage type!",
-                s
-            ))),
-            None => Err(AppError::Generic(format!(
-                "Storage type was not specified!"
-            ))),
         }
+        Ok(())
     }
 
     pub async fn new_from_storage_info(
@@ -145,20 +425,29 @@ impl Storage {
 
 #[async_trait]
 pub trait StorageModule: Send + Sync + Debug {
+    /// The options the module was created with
+    fn info(&self) -> StorageInfo;
+
     /// Controller creation, return type is volume_id for future requests
     async fn create(&self, name: &str, provision_size: i64) -> Result<String>;
 
     /// Controller deletion
     async fn delete(&self, volume_id: &str) -> Result<()>;
 
-    /// Controller publish
-    async fn publish(&self, volume_id: &str) -> Result<()>;
+    /// Controller publish, return type is the publish context handed to the node
+    async fn publish(&self, volume_id: &str) -> Result<HashMap<String, String>>;
 
     /// Controller unpublish
     async fn unpublish(&self, volume_id: &str) -> Result<()>;
 
-    /// Node stage
-    async fn stage(&self, volume_id: &str, staging_path: &str) -> Result<()>;
+    /// Node stage, mount flags are the options requested in the volume capability
+    async fn stage(
+        &self,
+        volume_id: &str,
+        staging_path: &str,
+        publish_context: &HashMap<String, String>,
+        mount_flags: &[String],
+    ) -> Result<()>;
 
     /// Node unstage
     async fn unstage(&self, volume_id: &str, staging_path: &str) -> Result<()>;
@@ -169,3 +458,90 @@ pub trait StorageModule: Send + Sync + Debug {
     /// Node unpublish
     async fn unmount(&self, volume_id: &str, target_path: &str) -> Result<()>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dataset(properties: &HashMap<String, String>) -> ZFSDataset {
+        let mut output = "tank/k8s/pvc-1\tvolsize\t1G\tlocal\n".to_string();
+        for (name, val) in properties {
+            output.push_str(&format!("tank/k8s/pvc-1\t{}\t{}\tlocal\n", name, val));
+        }
+        ZFSDataset::parse("tank/k8s/pvc-1".into(), &output)
+    }
+
+    fn round_trip(params: &[(&str, &str)]) {
+        let params: HashMap<String, String> = params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let info = StorageInfo::from_params(&params).unwrap();
+        let properties = info.properties();
+        assert!(properties.keys().all(|k| k.starts_with("metal-csi:")));
+        let restored = StorageInfo::from_properties(&dataset(&properties))
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.to_params(), info.to_params());
+    }
+
+    #[test]
+    fn iscsi_survives_dataset_properties() {
+        round_trip(&[
+            ("type", "iscsi"),
+            ("baseIqn", "iqn.2003-01.org.example"),
+            ("targetPortal", "[fd00::5]:3261"),
+            ("fsType", "xfs"),
+            ("deviceTimeout", "30"),
+            ("reclaimPolicy", "delete"),
+            ("zfs.parentDataset", "tank/k8s/"),
+            ("zfs.attr.compression", "lz4"),
+            ("attr.generate_node_acls", "1"),
+            ("backstore.attr.emulate_tpu", "1"),
+        ]);
+    }
+
+    #[test]
+    fn nfs_survives_dataset_properties() {
+        round_trip(&[
+            ("type", "nfs"),
+            ("host", "nas"),
+            ("export", "/export/k8s"),
+            ("zfs.parentDataset", "tank/nfs/"),
+        ]);
+    }
+
+    #[test]
+    fn reconcile_only_updates_published_keys() {
+        let mut recorded = HashMap::new();
+        recorded.insert("lun".to_string(), "0".to_string());
+        recorded.insert("publishedNode".to_string(), "node-1".to_string());
+        recorded.insert("type".to_string(), "iscsi".to_string());
+        let published = |lun: &str| {
+            let mut published = HashMap::new();
+            published.insert("lun".to_string(), lun.to_string());
+            published
+        };
+
+        assert!(merge_publish_context(&mut recorded, published("0")).is_empty());
+        assert_eq!(
+            merge_publish_context(&mut recorded, published("1")),
+            vec!["lun"]
+        );
+        assert_eq!(recorded["lun"], "1");
+        assert_eq!(recorded["publishedNode"], "node-1");
+        assert_eq!(recorded["type"], "iscsi");
+    }
+
+    #[test]
+    fn datasets_without_type_have_no_storage_info() {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "metal-csi:iqn".to_string(),
+            "iqn.2003-01.org.example".to_string(),
+        );
+        assert!(StorageInfo::from_properties(&dataset(&properties))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -1,6 +1,4 @@
-use std::fmt::Write;
-
-use crate::control::ControlModule;
+use crate::control::{shell_join, ControlModule};
 use crate::error::AppError;
 use crate::storage::FilesystemType;
 use crate::Result;
@@ -16,51 +14,52 @@ impl ControlModule {
 pub struct Mount(ControlModule);
 
 impl Mount {
-    pub async fn mount(&self, fs: &FilesystemType, device: &str, path: &str) -> Result<()> {
-        self.exec_checked(&format!("mkdir -p {}", path)).await?;
+    pub async fn mount(
+        &self,
+        fs: &FilesystemType,
+        device: &str,
+        path: &str,
+        options: &[String],
+    ) -> Result<()> {
+        self.exec_argv_checked("mkdir", &["-p", path]).await?;
         info!("Mounting device {} at path {}", device, path);
-        let mut cmd = format!("mount ");
+        let mut args = vec![];
         if let Some(s) = fs.mount_type() {
-            cmd.write_fmt(format_args!("-t {} ", s))?;
+            args.push("-t");
+            args.push(s);
         }
-        if let Some(o) = fs.mount_options() {
-            cmd.write_fmt(format_args!("-o {} ", o))?;
+        let mut all_options: Vec<&str> = fs.mount_options().into_iter().collect();
+        all_options.extend(options.iter().map(|o| o.as_str()));
+        let all_options = all_options.join(",");
+        if !all_options.is_empty() {
+            args.push("-o");
+            args.push(all_options.as_str());
         }
-        cmd.write_fmt(format_args!("'{}' '{}'", device, path))?;
+        args.push(device);
+        args.push(path);
 
-        debug!("Running mount command: {}", cmd);
-        let (output, code) = self.exec(&cmd).await?;
-        if code == 0 {
+        debug!("Running mount command: mount {:?}", args);
+        let output = self.exec_argv("mount", &args).await?;
+        if output.code == 32 && output.stderr.contains("already mounted") {
             return Ok(());
-        } else if code == 32 {
-            if output.contains("already mounted") {
-                return Ok(());
-            }
         }
-        Err(AppError::CommandFailed { code, output })
+        output.check(&shell_join("mount", &args))?;
+        Ok(())
     }
 
     pub async fn umount(&self, path: &str) -> Result<()> {
         info!("Unmounting {}", path);
-        let cmd = format!("umount '{}'", path);
-        let (output, code) = self.exec(&cmd).await?;
-        if code == 0 {
+        let output = self.exec_argv("umount", &[path]).await?;
+        if output.code == 32 && output.stderr.contains("not mounted") {
             return Ok(());
-        } else if code == 32 {
-            if output.contains("not mounted") {
-                return Ok(());
-            }
         }
-        Err(AppError::CommandFailed { code, output })
+        output.check(&shell_join("umount", &[path]))?;
+        Ok(())
     }
 
     pub async fn get_mount(&self, path: &str) -> Result<Option<MountDetail>> {
         let result = self
-            .exec_checked(&format!(
-                "findmnt -J -o '{}' {}",
-                MountDetail::COLUMNS,
-                path
-            ))
+            .exec_argv_checked("findmnt", &["-J", "-o", MountDetail::COLUMNS, path])
             .await;
         match result {
             Ok(output) => {
@@ -73,35 +72,57 @@ impl Mount {
 
     pub async fn get_mounts(&self) -> Result<Vec<MountDetail>> {
         let result = self
-            .exec_checked(&format!("findmnt -J -o '{}'", MountDetail::COLUMNS))
+            .exec_argv_checked("findmnt", &["-J", "-o", MountDetail::COLUMNS])
             .await?;
         let mdc: MountDetailContainer = serde_json::from_str(&result)?;
         Ok(mdc.filesystems)
     }
 
+    /// Every mount, `get_mounts` nests mounts below the mount they are on
+    pub async fn list_mounts(&self) -> Result<Vec<MountDetail>> {
+        fn flatten(mut mount: MountDetail, result: &mut Vec<MountDetail>) {
+            for child in std::mem::take(&mut mount.children) {
+                flatten(child, result);
+            }
+            result.push(mount);
+        }
+        let mut result = vec![];
+        for mount in self.get_mounts().await? {
+            flatten(mount, &mut result);
+        }
+        Ok(result)
+    }
+
     pub async fn get_block_device(&self, path: &str) -> Result<Option<BlockDevice>> {
         let result = self
-            .exec_checked(&format!(
-                "lsblk -J -o '{}' '{}'",
-                BlockDevice::COLUMNS,
-                path
-            ))
+            .exec_argv_checked("lsblk", &["-J", "-o", BlockDevice::COLUMNS, path])
             .await?;
         let mut bdc: BlockDeviceContainer = serde_json::from_str(&result)?;
         Ok(bdc.blockdevices.pop())
     }
 
+    /// Whether anything uses the disk: a mount of it or of a partition, or a device such as a
+    /// device mapper or multipath map built on it
+    pub async fn disk_in_use(&self, path: &str) -> Result<bool> {
+        let result = self
+            .exec_argv_checked("lsblk", &["-J", "-o", DiskUsage::COLUMNS, path])
+            .await?;
+        let container: DiskUsageContainer = serde_json::from_str(&result)?;
+        Ok(container
+            .blockdevices
+            .iter()
+            .any(|d| !d.children.is_empty() || d.mountpoint.is_some()))
+    }
+
     pub async fn mkfs(&self, path: &str, fs: &FilesystemType) -> Result<()> {
         info!("Creating a {} filesystem on {}", fs, path);
-        let cmd = format!(
-            "{} '{}'",
-            fs.mkfs().ok_or_else(|| AppError::Generic(format!(
+        let mkfs = fs.mkfs().ok_or_else(|| {
+            AppError::Generic(format!(
                 "Cannot make filesystem for {}",
                 fs.mount_type().unwrap_or_default()
-            )))?,
-            path
-        );
-        self.exec_checked(&cmd).await?;
+            ))
+        })?;
+        self.exec_argv_checked(mkfs, &[path]).await?;
         Ok(())
     }
 }
@@ -151,6 +172,24 @@ impl BlockDevice {
     const COLUMNS: &'static str = "name,rm,type,size,fstype,ro";
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiskUsageContainer {
+    blockdevices: Vec<DiskUsage>,
+}
+
+/// A block device with the devices that hold it as children
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct DiskUsage {
+    pub name: String,
+    pub mountpoint: Option<String>,
+    pub children: Vec<DiskUsage>,
+}
+
+impl DiskUsage {
+    const COLUMNS: &'static str = "name,mountpoint";
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Bool {
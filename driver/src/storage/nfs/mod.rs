@@ -24,6 +24,14 @@ impl NFSOptions {
 
         Ok(NFSOptions { host, export })
     }
+
+    /// The parameters that `new` reads back into these options
+    pub fn params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert("host".to_string(), self.host.to_string());
+        params.insert("export".to_string(), self.export.to_string());
+        params
+    }
 }
 
 #[derive(Debug)]
@@ -35,29 +43,55 @@ pub struct NFSModule {
 
 #[async_trait]
 impl StorageModule for NFSModule {
+    fn info(&self) -> StorageInfo {
+        StorageInfo::NFS {
+            options: self.options.clone(),
+            zfs: self.zfs.clone(),
+        }
+    }
+
     async fn create(&self, name: &str, _: i64) -> Result<String> {
         info!("Creating {}", name);
         let parent_dataset = self.zfs.parent_dataset.as_str();
         let dataset_name = format!("{}{}", parent_dataset, name);
+        ZFS::validate_name(&dataset_name)?;
         let zfs = self.control.zfs().await?;
         let dataset = zfs.get_dataset(dataset_name.as_str()).await?;
         if dataset.is_none() {
             zfs.create_dataset(dataset_name.as_str(), None).await?;
         }
         let mut attrs = self.zfs.attributes.clone();
+        attrs.extend(self.info().properties());
         attrs.insert("sharenfs".into(), self.options.export.to_string());
         zfs.set_attributes(&dataset_name, &attrs).await?;
         Ok(dataset_name)
     }
 
     async fn delete(&self, volume_id: &str) -> Result<()> {
-        info!("NFS Controller Delete, no action needed: {}", volume_id);
+        info!("Delete {}", volume_id);
+        ZFS::validate_name(volume_id)?;
+        match self.zfs.reclaim_policy {
+            ReclaimPolicy::Retain => {
+                warn!("[nfs] Retaining dataset of deleted volume '{}'", volume_id);
+                mark_retained(&self.control, volume_id).await?
+            }
+            ReclaimPolicy::Delete => self.control.zfs().await?.destroy_dataset(volume_id).await?,
+        }
         Ok(())
     }
 
-    async fn publish(&self, volume_id: &str) -> Result<()> {
-        info!("NFS Controller Publish, no action needed: {}", volume_id);
-        Ok(())
+    async fn publish(&self, volume_id: &str) -> Result<HashMap<String, String>> {
+        info!(
+            "NFS Controller Publish, recording volume properties: {}",
+            volume_id
+        );
+        ZFS::validate_name(volume_id)?;
+        self.control
+            .zfs()
+            .await?
+            .set_attributes(volume_id, &self.info().properties())
+            .await?;
+        Ok(Default::default())
     }
 
     async fn unpublish(&self, volume_id: &str) -> Result<()> {
@@ -65,7 +99,13 @@ impl StorageModule for NFSModule {
         Ok(())
     }
 
-    async fn stage(&self, volume_id: &str, _: &str) -> Result<()> {
+    async fn stage(
+        &self,
+        volume_id: &str,
+        _: &str,
+        _: &HashMap<String, String>,
+        _: &[String],
+    ) -> Result<()> {
         info!("NFS Node Stage, no action needed: {}", volume_id);
         Ok(())
     }
@@ -77,11 +117,12 @@ impl StorageModule for NFSModule {
 
     async fn mount(&self, volume_id: &str, _: &str, target_path: &str) -> Result<()> {
         info!("Mounting {}", volume_id);
+        ZFS::validate_name(volume_id)?;
         let nfs_path = format!("{}:/{}", self.options.host, volume_id);
         self.control
             .mounter()
             .await?
-            .mount(&FilesystemType::NFS, &nfs_path, target_path)
+            .mount(&FilesystemType::NFS, &nfs_path, target_path, &[])
             .await?;
         Ok(())
     }
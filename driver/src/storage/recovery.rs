@@ -0,0 +1,187 @@
+use super::mounter::Mount;
+use super::*;
+use futures::StreamExt;
+use std::collections::HashSet;
+use tokio::sync::OwnedMutexGuard;
+
+/// Volumes recovered at the same time
+const RECOVERY_CONCURRENCY: usize = 4;
+
+impl Storage {
+    /// The volumes staged on a node
+    pub async fn staged_on(metadata: &Metadata, node_id: &str) -> Result<Vec<Staging>> {
+        Ok(metadata
+            .list::<Staging>()
+            .await?
+            .into_iter()
+            .map(|(_, s)| s)
+            .filter(|s| s.node_id == node_id)
+            .collect())
+    }
+
+    /// Redo the stages and mounts of this node that a reboot or crash lost, kubelet assumes
+    /// they are still in place. Every volume comes with the lock that holds its requests back,
+    /// it is released once the volume is recovered. Sessions of driver targets that belong to
+    /// no staged volume and whose disks nothing uses are logged out.
+    pub async fn recover_node(
+        control: ControlModule,
+        metadata: &Metadata,
+        node_id: &str,
+        config: &Configuration,
+        volumes: Vec<(Staging, OwnedMutexGuard<()>)>,
+    ) -> Result<()> {
+        info!("Recovering {} staged volumes", volumes.len());
+        let mounter = control.mounter().await?;
+        let iscsiadm = control.get_iscsiadm().await?;
+        let mounted: HashSet<String> = mounter
+            .list_mounts()
+            .await?
+            .into_iter()
+            .map(|m| m.target)
+            .collect();
+        let sessions = iscsiadm.sessions().await?;
+
+        futures::stream::iter(volumes)
+            .for_each_concurrent(RECOVERY_CONCURRENCY, |(staging, lock)| {
+                let (control, mounter, mounted, sessions) =
+                    (&control, &mounter, &mounted, &sessions);
+                async move {
+                    let volume_id = staging.volume_id.as_str();
+                    let result = async {
+                        let storage_info =
+                            match Self::get_node_storage_info(volume_id, metadata).await? {
+                                Some(s) => s,
+                                None => {
+                                    warn!(
+                                        "Cannot recover volume '{}', it has no storage info",
+                                        volume_id
+                                    );
+                                    return Ok(());
+                                }
+                            };
+                        let session_lost = match &storage_info {
+                            StorageInfo::ISCSI { options, .. } => {
+                                let iqn = options.target_iqn(volume_id);
+                                !sessions.iter().any(|s| s.iqn == iqn)
+                            }
+                            _ => false,
+                        };
+                        let storage =
+                            Self::new_from_storage_info(storage_info, control.clone()).await?;
+                        Self::recover_volume(&storage, mounter, &staging, mounted, session_lost)
+                            .await
+                    };
+                    if let Err(e) = result.await {
+                        error!("Recovery of volume '{}' failed: {}", volume_id, e);
+                        VolumeState::record_error(metadata, volume_id, &e).await;
+                    }
+                    drop(lock);
+                }
+            })
+            .await;
+
+        Self::logout_unused_sessions(&mounter, metadata, node_id, config).await
+    }
+
+    async fn recover_volume(
+        storage: &Storage,
+        mounter: &Mount,
+        staging: &Staging,
+        mounted: &HashSet<String>,
+        session_lost: bool,
+    ) -> Result<()> {
+        let volume_id = staging.volume_id.as_str();
+        let staging_path = staging.staging_path.as_str();
+        let staged = mounted.contains(staging_path);
+        if staged && session_lost {
+            // The filesystem sits on a disk that went away with the session
+            warn!(
+                "iSCSI session of volume '{}' is gone, remounting {}",
+                volume_id, staging_path
+            );
+            for target_path in staging.target_paths.iter() {
+                mounter.umount(target_path).await?;
+            }
+            mounter.umount(staging_path).await?;
+        }
+        if !staged || session_lost {
+            info!("Staging volume '{}' at {} again", volume_id, staging_path);
+            storage
+                .stage(
+                    volume_id,
+                    staging_path,
+                    &staging.publish_context,
+                    &staging.mount_flags,
+                )
+                .await?;
+        }
+        for target_path in staging.target_paths.iter() {
+            if session_lost || !mounted.contains(target_path) {
+                info!("Mounting volume '{}' at {} again", volume_id, target_path);
+                storage.mount(volume_id, staging_path, target_path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Log out of driver targets that belong to no staged volume, unless a disk of the session
+    /// is mounted or held by another device such as a multipath map
+    async fn logout_unused_sessions(
+        mounter: &Mount,
+        metadata: &Metadata,
+        node_id: &str,
+        config: &Configuration,
+    ) -> Result<()> {
+        let iscsiadm = mounter.get_iscsiadm().await?;
+        // Sessions are listed before the records: a stage records the volume before it logs
+        // in, so a session that belongs to a volume always finds its record
+        let sessions = iscsiadm.sessions().await?;
+        let mut base_iqns: HashSet<String> = config.iscsi.base_iqn.iter().cloned().collect();
+        let mut staged_iqns = HashSet::new();
+        for staging in Self::staged_on(metadata, node_id).await? {
+            match Self::get_node_storage_info(&staging.volume_id, metadata).await {
+                Ok(Some(StorageInfo::ISCSI { options, .. })) => {
+                    staged_iqns.insert(options.target_iqn(&staging.volume_id));
+                    base_iqns.insert(options.base_iqn);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(
+                        "Keeping every iSCSI session, the target of volume '{}' is unknown: {}",
+                        staging.volume_id, e
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        for session in sessions {
+            let ours = base_iqns
+                .iter()
+                .any(|b| session.iqn.starts_with(&format!("{}:", b)));
+            if !ours || staged_iqns.contains(&session.iqn) {
+                continue;
+            }
+            let result = async {
+                for disk in iscsiadm.session_disks(&session).await? {
+                    if mounter.disk_in_use(&format!("/dev/{}", disk.name)).await? {
+                        warn!(
+                            "Session {:?} matches no staged volume but /dev/{} is in use, keeping it",
+                            session, disk.name
+                        );
+                        return Ok(());
+                    }
+                }
+                info!(
+                    "Logging out of session {:?}, it matches no staged volume",
+                    session
+                );
+                iscsiadm.logout_session(&session).await
+            };
+            if let Err(e) = result.await {
+                error!("Could not clean up session {:?}: {}", session, e);
+            }
+        }
+        Ok(())
+    }
+}
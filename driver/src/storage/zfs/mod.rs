@@ -1,11 +1,18 @@
 use super::*;
-use crate::{control::ControlModule, Result};
+use crate::{config::ReclaimPolicy, control::ControlModule, Result};
+use regex::Regex;
 use std::collections::HashMap;
 
+lazy_static! {
+    static ref DATASET_NAME: Regex =
+        Regex::new(r"^[A-Za-z0-9][A-Za-z0-9_.:-]*(/[A-Za-z0-9_.:-]+)*$").unwrap();
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ZFSOptions {
     pub parent_dataset: String,
     pub attributes: HashMap<String, String>,
+    pub reclaim_policy: ReclaimPolicy,
 }
 
 impl ZFSOptions {
@@ -16,6 +23,7 @@ impl ZFSOptions {
             .get("zfs.parentDataset")
             .ok_or_else(|| AppError::Generic(format!("ZFS Parent Dataset is required!")))?
             .to_string();
+        ZFS::validate_name(parent_dataset.trim_end_matches('/'))?;
         if !parent_dataset.ends_with("/") {
             parent_dataset.push_str("/");
         }
@@ -25,11 +33,33 @@ impl ZFSOptions {
                 attributes.insert(k.to_string().split_off(9), v.to_string());
             }
         }
+        let reclaim_policy = match params.get("reclaimPolicy") {
+            Some(p) => p.parse()?,
+            None => ReclaimPolicy::default(),
+        };
         Ok(ZFSOptions {
             parent_dataset,
             attributes,
+            reclaim_policy,
         })
     }
+
+    /// The parameters that `new` reads back into these options
+    pub fn params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        params.insert(
+            "zfs.parentDataset".to_string(),
+            self.parent_dataset.to_string(),
+        );
+        params.insert(
+            "reclaimPolicy".to_string(),
+            self.reclaim_policy.as_str().to_string(),
+        );
+        for (k, v) in self.attributes.iter() {
+            params.insert(format!("{}{}", Self::ATTR_PREFIX, k), v.to_string());
+        }
+        params
+    }
 }
 
 impl ControlModule {
@@ -43,15 +73,27 @@ impl ControlModule {
 pub struct ZFS(ControlModule);
 
 impl ZFS {
+    /// Longest dataset name ZFS accepts
+    const MAX_NAME_LEN: usize = 255;
+
+    /// Reject names that aren't plain ZFS dataset paths before they reach a command line
+    pub fn validate_name(name: &str) -> Result<()> {
+        let valid = name.len() <= Self::MAX_NAME_LEN
+            && DATASET_NAME.is_match(name)
+            && name.split('/').all(|c| c != "." && c != "..");
+        if valid {
+            Ok(())
+        } else {
+            Err(AppError::InvalidArgument(format!(
+                "'{}' is not a valid dataset name",
+                name
+            )))
+        }
+    }
+
     pub async fn list_datasets(&self) -> Result<Vec<ZFSDatasetEntry>> {
         let mut result = vec![];
-        let (output, code) = self.exec("zfs list -H").await?;
-        if code != 0 {
-            return Err(AppError::Generic(format!(
-                "ZFS list failed with code {}!\n{}",
-                code, output
-            )));
-        }
+        let output = self.exec_argv_checked("zfs", &["list", "-H"]).await?;
         for line in output.split("\n") {
             let props: Vec<&str> = line.split("\t").collect();
             if props.len() != 5 {
@@ -68,28 +110,53 @@ impl ZFS {
         Ok(result)
     }
 
-    pub async fn get_dataset<T: Into<String>>(&self, name: T) -> Result<Option<ZFSDataset>> {
-        let name = name.into();
-        let cmd = format!("zfs get -H all '{}'", name);
-        let (output, code) = self.exec(&cmd).await?;
-        if code == 1 {
-            return Ok(None);
+    /// Every dataset below a parent, including it, with the given properties where they are set.
+    /// A parent that doesn't exist has no datasets.
+    pub async fn list_properties(
+        &self,
+        parent: &str,
+        properties: &[&str],
+    ) -> Result<Vec<(String, HashMap<String, String>)>> {
+        Self::validate_name(parent)?;
+        let columns = std::iter::once("name")
+            .chain(properties.iter().copied())
+            .collect::<Vec<_>>()
+            .join(",");
+        let output = self
+            .exec_argv("zfs", &["list", "-H", "-p", "-r", "-o", &columns, parent])
+            .await?;
+        if output.code == 1 && output.stderr.contains("dataset does not exist") {
+            return Ok(vec![]);
         }
-        let mut properties = HashMap::new();
-        for line in output.split("\n") {
-            let props: Vec<&str> = line.split("\t").collect();
-            if props.len() != 4 {
+        let output = output.check(&format!("zfs list -H -p -r -o {} {}", columns, parent))?;
+        let mut result = vec![];
+        for line in output.stdout.lines() {
+            let values: Vec<&str> = line.split('\t').collect();
+            if values.len() != properties.len() + 1 {
                 continue;
             }
-            properties.insert(
-                props[1].into(),
-                ZFSProperty {
-                    value: props[2].into(),
-                    source: props[3].into(),
-                },
-            );
+            let props = properties
+                .iter()
+                .zip(values[1..].iter())
+                .filter(|(_, v)| **v != "-")
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            result.push((values[0].to_string(), props));
+        }
+        Ok(result)
+    }
+
+    pub async fn get_dataset<T: Into<String>>(&self, name: T) -> Result<Option<ZFSDataset>> {
+        let name = name.into();
+        Self::validate_name(&name)?;
+        let output = self
+            .exec_argv("zfs", &["get", "-H", "all", name.as_str()])
+            .await?;
+        if output.code == 1 && output.stderr.contains("dataset does not exist") {
+            return Ok(None);
         }
-        Ok(Some(ZFSDataset { properties, name }))
+        let output = output.check(&format!("zfs get -H all {}", name))?;
+        Ok(Some(ZFSDataset::parse(name, &output.stdout)))
     }
 
     pub async fn set_attributes(
@@ -97,25 +164,24 @@ impl ZFS {
         dataset: &str,
         attrs: &HashMap<String, String>,
     ) -> Result<()> {
+        Self::validate_name(dataset)?;
         if attrs.len() > 0 {
-            let mut cmd = "zfs set ".to_string();
-            for (key, val) in attrs.iter() {
-                cmd.push_str(&format!("'{}={}' ", key, val));
-            }
-            cmd.push_str(dataset);
-            self.exec_checked(&cmd).await?;
+            let props: Vec<String> = attrs
+                .iter()
+                .map(|(key, val)| format!("{}={}", key, val))
+                .collect();
+            let mut args = vec!["set"];
+            args.extend(props.iter().map(|p| p.as_str()));
+            args.push(dataset);
+            self.exec_argv_checked("zfs", &args).await?;
         }
         Ok(())
     }
 
     pub async fn create_dataset<T: Into<String>>(&self, name: T, size: Option<i64>) -> Result<()> {
         let name = name.into();
+        Self::validate_name(&name)?;
         debug!("Creating ZFS dataset with name '{}'", name);
-        let vopt = if let Some(s) = size {
-            format!("-V {}", s)
-        } else {
-            Default::default()
-        };
         if name.contains("/") {
             let mut parts: Vec<&str> = name.split("/").collect();
             let mut curpath = parts.remove(0).to_string();
@@ -123,21 +189,36 @@ impl ZFS {
                 let part = parts.remove(0);
                 curpath = format!("{}/{}", curpath, part);
                 debug!("Creating parent path '{}'", curpath);
-                self.exec(&format!("zfs create '{}'", curpath)).await?;
+                self.exec_argv("zfs", &["create", curpath.as_str()]).await?;
             }
         }
-        let cmd = format!("zfs create {} '{}'", vopt, name);
-        let (output, code) = self.exec(&cmd).await?;
-        if code != 0 {
-            return Err(AppError::Generic(
-                format!(
-                    "Failed to create ZFS dataset, exit code {}\n{}",
-                    code, output
-                )
-                .trim()
-                .to_string(),
-            ));
+        let size = size.map(|s| s.to_string());
+        let mut args = vec!["create"];
+        if let Some(s) = &size {
+            args.push("-V");
+            args.push(s.as_str());
         }
+        args.push(name.as_str());
+        self.exec_argv_checked("zfs", &args).await?;
+        Ok(())
+    }
+
+    pub async fn rename_dataset(&self, name: &str, new_name: &str) -> Result<()> {
+        Self::validate_name(name)?;
+        Self::validate_name(new_name)?;
+        info!("Renaming ZFS dataset '{}' to '{}'", name, new_name);
+        self.exec_argv_checked("zfs", &["rename", name, new_name])
+            .await?;
+        Ok(())
+    }
+
+    /// Destroy a dataset, succeeding if it is already gone
+    pub async fn destroy_dataset(&self, name: &str) -> Result<()> {
+        if self.get_dataset(name).await?.is_none() {
+            return Ok(());
+        }
+        info!("Destroying ZFS dataset '{}'", name);
+        self.exec_argv_checked("zfs", &["destroy", name]).await?;
         Ok(())
     }
 }
@@ -157,6 +238,45 @@ pub struct ZFSDataset {
     properties: HashMap<String, ZFSProperty>,
 }
 
+impl ZFSDataset {
+    /// A dataset from the output of `zfs get -H all`
+    pub fn parse(name: String, output: &str) -> Self {
+        let mut properties = HashMap::new();
+        for line in output.split("\n") {
+            let props: Vec<&str> = line.split("\t").collect();
+            if props.len() != 4 {
+                continue;
+            }
+            properties.insert(
+                props[1].into(),
+                ZFSProperty {
+                    value: props[2].into(),
+                    source: props[3].into(),
+                },
+            );
+        }
+        ZFSDataset { properties, name }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&str> {
+        self.properties.get(name).map(|p| p.value.as_str())
+    }
+
+    /// User properties whose names start with the prefix, keyed by the rest of the name
+    pub fn user_properties(&self, prefix: &str) -> HashMap<String, String> {
+        self.properties
+            .iter()
+            .filter(|(name, p)| name.starts_with(prefix) && p.value != "-")
+            .map(|(name, p)| (name[prefix.len()..].to_string(), p.value.to_string()))
+            .collect()
+    }
+
+    /// Volumes created without a reservation only consume the space actually written
+    pub fn is_sparse(&self) -> bool {
+        self.property("refreservation") == Some("none")
+    }
+}
+
 #[derive(Debug)]
 pub struct ZFSProperty {
     value: String,